@@ -6,9 +6,12 @@
 #![allow(clippy::all)]
 
 #![allow(unused_attributes)]
-#![rustfmt::skip]
+#![cfg_attr(rustfmt, rustfmt::skip)]
 
+#![allow(renamed_and_removed_lints)]
 #![allow(box_pointers)]
+#![allow(unused_parens)]
+#![allow(mismatched_lifetime_syntaxes)]
 #![allow(dead_code)]
 #![allow(missing_docs)]
 #![allow(non_camel_case_types)]
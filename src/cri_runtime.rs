@@ -0,0 +1,552 @@
+use crate::cri::{
+    ContainerConfig, ContainerStats, ContainerStatsRequest, ContainerStatsResponse,
+    ContainerStatusRequest, ContainerStatusResponse, CreateContainerRequest,
+    CreateContainerResponse, ExecSyncRequest, ExecSyncResponse, ImageSpec, LinuxContainerResources,
+    PodSandboxConfig, PullImageRequest, PullImageResponse, RemoveContainerRequest,
+    RemoveContainerResponse, RemovePodSandboxRequest, RemovePodSandboxResponse,
+    RunPodSandboxRequest, RunPodSandboxResponse, StartContainerRequest, StartContainerResponse,
+    StopContainerRequest, StopContainerResponse, StopPodSandboxRequest, StopPodSandboxResponse,
+    UpdateContainerResourcesRequest, UpdateContainerResourcesResponse, VersionRequest,
+    VersionResponse,
+};
+use bytes::{Buf, BufMut};
+use http::uri::PathAndQuery;
+use hyper_util::rt::TokioIo;
+use protobuf::Message;
+use std::{collections::HashMap, io, marker::PhantomData, path::Path, sync::Arc};
+use tokio::{net::UnixStream, runtime::Runtime};
+use tonic::{
+    client::Grpc,
+    codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder},
+    transport::{Channel, Endpoint, Uri},
+    Status,
+};
+
+const RUNTIME_SERVICE: &str = "runtime.v1.RuntimeService";
+const IMAGE_SERVICE: &str = "runtime.v1.ImageService";
+
+/// A client of the Kubernetes CRI RuntimeService and ImageService, as
+/// served by containerd or CRI-O on a unix socket.
+#[derive(Clone)]
+pub struct CriRuntime {
+    channel: Channel,
+    // tonic runs on tokio while the judge runs on async-std, so calls are
+    // spawned on a runtime of the client
+    runtime: Arc<Runtime>,
+}
+
+impl CriRuntime {
+    pub async fn connect(socket: &Path) -> Result<Self, String> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("cri")
+            .enable_all()
+            .build()
+            .map_err(|err| format!("failed to start cri runtime: {}", err))?;
+
+        // the uri is ignored, every connection goes to the socket
+        let socket = socket.to_path_buf();
+        let connector = {
+            let socket = socket.clone();
+            tower::service_fn(move |_: Uri| {
+                let socket = socket.clone();
+                async move { Ok::<_, io::Error>(TokioIo::new(UnixStream::connect(socket).await?)) }
+            })
+        };
+        let channel = runtime
+            .spawn(async move {
+                Endpoint::from_static("http://localhost")
+                    .connect_with_connector(connector)
+                    .await
+            })
+            .await
+            .map_err(|err| err.to_string())
+            .and_then(|channel| channel.map_err(|err| error_chain(&err)))
+            .map_err(|err| format!("failed to connect to {}: {}", socket.display(), err))?;
+
+        Ok(Self {
+            channel,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    pub async fn version(&self) -> Result<VersionResponse, String> {
+        let mut request = VersionRequest::new();
+        request.set_version("v1".to_string());
+        self.call(RUNTIME_SERVICE, "Version", request).await
+    }
+
+    pub async fn pull_image(&self, image: &str) -> Result<String, String> {
+        let mut spec = ImageSpec::new();
+        spec.set_image(image.to_string());
+        let mut request = PullImageRequest::new();
+        request.set_image(spec);
+
+        let response: PullImageResponse = self.call(IMAGE_SERVICE, "PullImage", request).await?;
+        Ok(response.image_ref)
+    }
+
+    pub async fn run_pod_sandbox(&self, config: PodSandboxConfig) -> Result<String, String> {
+        let mut request = RunPodSandboxRequest::new();
+        request.set_config(config);
+
+        let response: RunPodSandboxResponse =
+            self.call(RUNTIME_SERVICE, "RunPodSandbox", request).await?;
+        Ok(response.pod_sandbox_id)
+    }
+
+    pub async fn stop_pod_sandbox(&self, pod_sandbox_id: &str) -> Result<(), String> {
+        let mut request = StopPodSandboxRequest::new();
+        request.set_pod_sandbox_id(pod_sandbox_id.to_string());
+
+        let _: StopPodSandboxResponse = self
+            .call(RUNTIME_SERVICE, "StopPodSandbox", request)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn remove_pod_sandbox(&self, pod_sandbox_id: &str) -> Result<(), String> {
+        let mut request = RemovePodSandboxRequest::new();
+        request.set_pod_sandbox_id(pod_sandbox_id.to_string());
+
+        let _: RemovePodSandboxResponse = self
+            .call(RUNTIME_SERVICE, "RemovePodSandbox", request)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn create_container(
+        &self,
+        pod_sandbox_id: &str,
+        config: ContainerConfig,
+        sandbox_config: PodSandboxConfig,
+    ) -> Result<String, String> {
+        let mut request = CreateContainerRequest::new();
+        request.set_pod_sandbox_id(pod_sandbox_id.to_string());
+        request.set_config(config);
+        request.set_sandbox_config(sandbox_config);
+
+        let response: CreateContainerResponse = self
+            .call(RUNTIME_SERVICE, "CreateContainer", request)
+            .await?;
+        Ok(response.container_id)
+    }
+
+    pub async fn start_container(&self, container_id: &str) -> Result<(), String> {
+        let mut request = StartContainerRequest::new();
+        request.set_container_id(container_id.to_string());
+
+        let _: StartContainerResponse = self
+            .call(RUNTIME_SERVICE, "StartContainer", request)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn stop_container(&self, container_id: &str, timeout: i64) -> Result<(), String> {
+        let mut request = StopContainerRequest::new();
+        request.set_container_id(container_id.to_string());
+        request.set_timeout(timeout);
+
+        let _: StopContainerResponse = self.call(RUNTIME_SERVICE, "StopContainer", request).await?;
+        Ok(())
+    }
+
+    pub async fn remove_container(&self, container_id: &str) -> Result<(), String> {
+        let mut request = RemoveContainerRequest::new();
+        request.set_container_id(container_id.to_string());
+
+        let _: RemoveContainerResponse = self
+            .call(RUNTIME_SERVICE, "RemoveContainer", request)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn update_container_resources(
+        &self,
+        container_id: &str,
+        resources: LinuxContainerResources,
+    ) -> Result<(), String> {
+        let mut request = UpdateContainerResourcesRequest::new();
+        request.set_container_id(container_id.to_string());
+        request.set_linux(resources);
+
+        let _: UpdateContainerResourcesResponse = self
+            .call(RUNTIME_SERVICE, "UpdateContainerResources", request)
+            .await?;
+        Ok(())
+    }
+
+    /// Runs `cmd` in the container and waits at most `timeout` seconds for
+    /// it, zero meaning no timeout.
+    pub async fn exec_sync(
+        &self,
+        container_id: &str,
+        cmd: Vec<String>,
+        timeout: i64,
+    ) -> Result<ExecSyncResponse, String> {
+        let mut request = ExecSyncRequest::new();
+        request.set_container_id(container_id.to_string());
+        request.set_cmd(cmd.into());
+        request.set_timeout(timeout);
+
+        self.call(RUNTIME_SERVICE, "ExecSync", request).await
+    }
+
+    pub async fn container_stats(&self, container_id: &str) -> Result<ContainerStats, String> {
+        let mut request = ContainerStatsRequest::new();
+        request.set_container_id(container_id.to_string());
+
+        let mut response: ContainerStatsResponse = self
+            .call(RUNTIME_SERVICE, "ContainerStats", request)
+            .await?;
+        Ok(response.take_stats())
+    }
+
+    /// The verbose information the runtime has on a container, whose `info`
+    /// holds the pid of its init process on containerd and CRI-O.
+    pub async fn container_info(
+        &self,
+        container_id: &str,
+    ) -> Result<HashMap<String, String>, String> {
+        let mut request = ContainerStatusRequest::new();
+        request.set_container_id(container_id.to_string());
+        request.set_verbose(true);
+
+        let mut response: ContainerStatusResponse = self
+            .call(RUNTIME_SERVICE, "ContainerStatus", request)
+            .await?;
+        Ok(response.take_info())
+    }
+
+    async fn call<Req: Message, Resp: Message>(
+        &self,
+        service: &str,
+        method: &str,
+        request: Req,
+    ) -> Result<Resp, String> {
+        let path = format!("/{}/{}", service, method)
+            .parse::<PathAndQuery>()
+            .map_err(|err| err.to_string())?;
+        let mut grpc = Grpc::new(self.channel.clone());
+        let response = self
+            .runtime
+            .spawn(async move {
+                grpc.ready()
+                    .await
+                    .map_err(|err| Status::unavailable(error_chain(&err)))?;
+                grpc.unary(tonic::Request::new(request), path, ProtobufCodec::default())
+                    .await
+            })
+            .await
+            .map_err(|err| format!("{} failed: {}", method, err))?
+            .map_err(|status| {
+                format!(
+                    "{} failed with status {:?}: {}",
+                    method,
+                    status.code(),
+                    status.message()
+                )
+            })?;
+
+        Ok(response.into_inner())
+    }
+}
+
+/// Formats an error along with its sources, which tell why a transport
+/// error happened.
+fn error_chain(err: &(dyn std::error::Error + 'static)) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(&format!(": {}", err));
+        source = err.source();
+    }
+    message
+}
+
+/// Encodes and decodes the messages of the CRI, which are generated by
+/// rust-protobuf, for tonic.
+struct ProtobufCodec<Req, Resp>(PhantomData<fn(Req) -> Resp>);
+
+impl<Req, Resp> Default for ProtobufCodec<Req, Resp> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<Req: Message, Resp: Message> Codec for ProtobufCodec<Req, Resp> {
+    type Encode = Req;
+    type Decode = Resp;
+    type Encoder = Self;
+    type Decoder = Self;
+
+    fn encoder(&mut self) -> Self {
+        Self::default()
+    }
+
+    fn decoder(&mut self) -> Self {
+        Self::default()
+    }
+}
+
+impl<Req: Message, Resp> Encoder for ProtobufCodec<Req, Resp> {
+    type Item = Req;
+    type Error = Status;
+
+    fn encode(&mut self, item: Req, dst: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        item.write_to_writer(&mut dst.writer())
+            .map_err(|err| Status::internal(format!("failed to encode message: {}", err)))
+    }
+}
+
+impl<Req, Resp: Message> Decoder for ProtobufCodec<Req, Resp> {
+    type Item = Resp;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<Resp>, Status> {
+        let message = src.copy_to_bytes(src.remaining());
+        Resp::parse_from_bytes(&message)
+            .map(Some)
+            .map_err(|err| Status::internal(format!("failed to decode message: {}", err)))
+    }
+}
+
+#[cfg(test)]
+// tonic handlers return its status as they are
+#[allow(clippy::result_large_err)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::HashMap,
+        convert::Infallible,
+        future::Future,
+        path::PathBuf,
+        pin::Pin,
+        sync::Mutex,
+        task::{Context, Poll},
+    };
+    use tempfile::TempDir;
+    use tokio::net::UnixListener;
+    use tokio_stream::wrappers::UnixListenerStream;
+    use tonic::{body::BoxBody, server::NamedService, transport::Server};
+
+    /// A RuntimeService keeping track of the containers created on it, which
+    /// runs commands in started ones by echoing them.
+    #[derive(Clone, Default)]
+    struct FakeRuntime {
+        // whether each container was started
+        containers: Arc<Mutex<HashMap<String, bool>>>,
+    }
+
+    impl FakeRuntime {
+        fn version(&self, _: VersionRequest) -> Result<VersionResponse, Status> {
+            let mut response = VersionResponse::new();
+            response.set_runtime_name("fake".to_string());
+            response.set_runtime_version("1.0.0".to_string());
+            Ok(response)
+        }
+
+        fn create_container(
+            &self,
+            request: CreateContainerRequest,
+        ) -> Result<CreateContainerResponse, Status> {
+            let mut containers = self.containers.lock().unwrap();
+            let id = format!("{}-{}", request.get_pod_sandbox_id(), containers.len());
+            containers.insert(id.clone(), false);
+            let mut response = CreateContainerResponse::new();
+            response.set_container_id(id);
+            Ok(response)
+        }
+
+        fn start_container(
+            &self,
+            request: StartContainerRequest,
+        ) -> Result<StartContainerResponse, Status> {
+            match self
+                .containers
+                .lock()
+                .unwrap()
+                .get_mut(request.get_container_id())
+            {
+                Some(started) => {
+                    *started = true;
+                    Ok(StartContainerResponse::new())
+                }
+                None => Err(Status::not_found(format!(
+                    "container {} does not exist",
+                    request.get_container_id()
+                ))),
+            }
+        }
+
+        fn container_status(
+            &self,
+            request: ContainerStatusRequest,
+        ) -> Result<ContainerStatusResponse, Status> {
+            if !self
+                .containers
+                .lock()
+                .unwrap()
+                .contains_key(request.get_container_id())
+            {
+                return Err(Status::not_found(format!(
+                    "container {} does not exist",
+                    request.get_container_id()
+                )));
+            }
+            let mut response = ContainerStatusResponse::new();
+            if request.get_verbose() {
+                response
+                    .mut_info()
+                    .insert("info".to_string(), r#"{"pid":42}"#.to_string());
+            }
+            Ok(response)
+        }
+
+        fn exec_sync(&self, request: ExecSyncRequest) -> Result<ExecSyncResponse, Status> {
+            let containers = self.containers.lock().unwrap();
+            if containers.get(request.get_container_id()) != Some(&true) {
+                return Err(Status::failed_precondition(format!(
+                    "container {} is not running",
+                    request.get_container_id()
+                )));
+            }
+            let mut response = ExecSyncResponse::new();
+            response.set_stdout(request.get_cmd().join(" ").into_bytes());
+            Ok(response)
+        }
+    }
+
+    impl NamedService for FakeRuntime {
+        const NAME: &'static str = RUNTIME_SERVICE;
+    }
+
+    impl tower::Service<http::Request<BoxBody>> for FakeRuntime {
+        type Response = http::Response<BoxBody>;
+        type Error = Infallible;
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<BoxBody>) -> Self::Future {
+            let fake = self.clone();
+            Box::pin(async move {
+                let method = request.uri().path().rsplit('/').next().unwrap_or_default();
+                Ok(match method {
+                    "Version" => unary(request, move |request| fake.version(request)).await,
+                    "CreateContainer" => {
+                        unary(request, move |request| fake.create_container(request)).await
+                    }
+                    "StartContainer" => {
+                        unary(request, move |request| fake.start_container(request)).await
+                    }
+                    "ContainerStatus" => {
+                        unary(request, move |request| fake.container_status(request)).await
+                    }
+                    "ExecSync" => unary(request, move |request| fake.exec_sync(request)).await,
+                    method => Status::unimplemented(method.to_string()).into_http(),
+                })
+            })
+        }
+    }
+
+    async fn unary<Req: Message, Resp: Message>(
+        request: http::Request<BoxBody>,
+        handler: impl Fn(Req) -> Result<Resp, Status> + Send + 'static,
+    ) -> http::Response<BoxBody> {
+        let service = tower::service_fn(move |request: tonic::Request<Req>| {
+            futures::future::ready(handler(request.into_inner()).map(tonic::Response::new))
+        });
+        tonic::server::Grpc::new(ProtobufCodec::<Resp, Req>::default())
+            .unary(service, request)
+            .await
+    }
+
+    /// Serves a fake runtime on a socket in a fresh directory, for as long as
+    /// the returned runtime is alive.
+    fn serve(fake: FakeRuntime) -> (TempDir, PathBuf, Runtime) {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("cri.sock");
+        let server = Runtime::new().unwrap();
+        let listener = server
+            .block_on(async { UnixListener::bind(&socket) })
+            .unwrap();
+        server.spawn(
+            Server::builder()
+                .add_service(fake)
+                .serve_with_incoming(UnixListenerStream::new(listener)),
+        );
+        (dir, socket, server)
+    }
+
+    #[test]
+    fn version() {
+        let (_dir, socket, _server) = serve(FakeRuntime::default());
+        let version = async_std::task::block_on(async {
+            CriRuntime::connect(&socket).await?.version().await
+        })
+        .unwrap();
+        assert_eq!(version.runtime_name, "fake");
+        assert_eq!(version.runtime_version, "1.0.0");
+    }
+
+    #[test]
+    fn create_start_and_exec() {
+        let (_dir, socket, _server) = serve(FakeRuntime::default());
+        async_std::task::block_on(async {
+            let runtime = CriRuntime::connect(&socket).await.unwrap();
+            let container = runtime
+                .create_container("pod", ContainerConfig::new(), PodSandboxConfig::new())
+                .await
+                .unwrap();
+            assert_eq!(container, "pod-0");
+
+            let cmd = vec!["echo".to_string(), "hello".to_string()];
+            let err = runtime
+                .exec_sync(&container, cmd.clone(), 0)
+                .await
+                .unwrap_err();
+            assert!(err.contains("FailedPrecondition"), "{}", err);
+
+            runtime.start_container(&container).await.unwrap();
+            let info = runtime.container_info(&container).await.unwrap();
+            assert_eq!(info["info"], r#"{"pid":42}"#);
+            let response = runtime.exec_sync(&container, cmd, 0).await.unwrap();
+            assert_eq!(response.stdout, b"echo hello");
+            assert_eq!(response.exit_code, 0);
+        });
+    }
+
+    #[test]
+    fn reports_error_status() {
+        let (_dir, socket, _server) = serve(FakeRuntime::default());
+        async_std::task::block_on(async {
+            let runtime = CriRuntime::connect(&socket).await.unwrap();
+            let err = runtime.start_container("missing").await.unwrap_err();
+            assert_eq!(
+                err,
+                "StartContainer failed with status NotFound: container missing does not exist"
+            );
+
+            // only the RuntimeService is served
+            let err = runtime.pull_image("gcc").await.unwrap_err();
+            assert!(
+                err.starts_with("PullImage failed with status Unimplemented"),
+                "{}",
+                err
+            );
+        });
+    }
+
+    #[test]
+    fn fails_to_connect_without_runtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("cri.sock");
+        let err = async_std::task::block_on(CriRuntime::connect(&socket))
+            .err()
+            .unwrap();
+        assert!(err.starts_with("failed to connect to"), "{}", err);
+    }
+}
@@ -1,33 +1,48 @@
 mod cri;
+#[cfg(unix)]
+mod cri_runtime;
+mod metrics;
+mod migration;
 mod queue;
 mod schema;
+#[cfg(unix)]
+mod signal;
+mod telemetry;
+mod validate;
 mod worker;
 
+use async_std::channel;
 use clap::Clap;
-use concurrent_queue::ConcurrentQueue;
-use lapin::{types::LongLongUInt, Channel};
-use log::{error, info};
+#[cfg(target_os = "linux")]
+use cri_runtime::CriRuntime;
+#[cfg(unix)]
+use futures::FutureExt;
+#[cfg(unix)]
+use nix::sys::signal::Signal;
 use once_cell::sync::OnceCell;
-use queue::{Queue, QueuePublisher, QueueSubscriber};
-use schema::{JudgeConfig, JudgeResult, Program};
-use std::{convert::TryInto, thread};
-use std_semaphore::Semaphore;
+use queue::{DeadLetter, Queue, QueueSubscriber};
+use schema::{JudgeConfig, JudgeResult};
+use std::{fs, path::PathBuf, process, thread, time::Duration};
 #[cfg(target_os = "linux")]
-use worker::{linux_worker::LinuxWorker, worker::Worker};
+use std::{path::Path, sync::Arc};
+use tracing::{error, field, info, info_span, Instrument};
 #[cfg(target_os = "windows")]
-use worker::{windows_worker::WindowsWorker, worker::Worker};
+use worker::windows_worker::WindowsWorker;
+#[cfg(target_os = "linux")]
+use worker::{
+    cri_executor::CriExecutor, executor::Executor, judge::Judge, language::Languages,
+    linux_worker::LinuxWorker, native_executor::NativeExecutor, preset::Presets,
+};
+use worker::{
+    pool::Pool,
+    worker::{Dispatcher, PlatformWorker},
+};
 
 #[macro_use]
 extern crate lazy_static;
 
 lazy_static! {
-    static ref WORK_QUEUE: OnceCell<ConcurrentQueue<(
-        /* channel */ Channel,
-        /* delivery tag */ LongLongUInt,
-        /* judge config */ JudgeConfig,
-    )>> = OnceCell::new();
-
-    static ref WORKER_SEMAPHORE: OnceCell<Semaphore> = OnceCell::new();
+    static ref MESSAGE_QUEUE: OnceCell<Queue> = OnceCell::new();
 }
 
 #[derive(Clap)]
@@ -38,7 +53,20 @@ lazy_static! {
 struct Opts {
     /// The number of workers
     #[clap(short, long, default_value = "4")]
-    worker: i32,
+    worker: u16,
+    /// The file holding the number of workers instead, read again on SIGHUP to
+    /// resize the pool
+    #[clap(long)]
+    worker_file: Option<String>,
+    /// Pins each worker to a share of the cores, which keeps timings stable
+    #[clap(long)]
+    pin_cores: bool,
+    /// The address to serve Prometheus metrics at /metrics on, such as 0.0.0.0:9100
+    #[clap(long)]
+    metrics: Option<String>,
+    /// The seconds workers have to finish their requests on SIGTERM or SIGINT
+    #[clap(long, default_value = "60")]
+    shutdown_timeout: u64,
     /// The url of message queue
     #[clap(short, long, default_value = "amqp://localhost:5672")]
     url: String,
@@ -51,43 +79,326 @@ struct Opts {
     /// The routing key of message queue
     #[clap(short, long)]
     routing_key: Option<String>,
+    /// The exchange to publish judge results to when a request has no reply_to,
+    /// defaults to the default exchange
+    #[clap(long)]
+    result_exchange: Option<String>,
+    /// The routing key to publish judge results with when a request has no reply_to
+    #[clap(long)]
+    result_routing_key: Option<String>,
+    /// The exchange requests are dead-lettered to, along with the queue bound to it
+    #[clap(long, default_value = "rayjudge.dead-letter")]
+    dead_letter_exchange: String,
+    /// The number of times judging a request may fail before it is dead-lettered
+    #[clap(long, default_value = "3")]
+    max_attempts: u32,
+    /// The seconds a request that failed to be judged waits before it is retried
+    #[clap(long, default_value = "10")]
+    retry_delay: u64,
+    /// The directory containing program sources and testcases
+    #[clap(short, long, default_value = ".", global = true)]
+    data: String,
+    /// The directory in which sandboxes are created
+    #[clap(long, default_value = "/tmp/rayjudge", global = true)]
+    workdir: String,
+    /// The cgroup v2 directory below which sandboxed runs are limited
+    #[clap(long, default_value = "/sys/fs/cgroup/rayjudge", global = true)]
+    cgroup: String,
+    /// The backend programs are run with: native or cri
+    #[clap(long, default_value = "native", possible_values = &["native", "cri"], global = true)]
+    executor: String,
+    /// The unix socket of the CRI runtime used by the cri executor
+    #[clap(long, default_value = "/run/containerd/containerd.sock", global = true)]
+    cri_socket: String,
+    /// The container image the cri executor runs languages without an image in
+    #[clap(long, default_value = "docker.io/library/gcc:latest", global = true)]
+    cri_image: String,
+    /// The number of replicas each worker runs at once, defaults to the cores per worker
+    #[clap(long, global = true)]
+    parallel: Option<usize>,
+    /// The TOML or JSON file describing the supported languages
+    #[clap(long, global = true)]
+    languages: Option<String>,
+    /// The TOML or JSON file defining stage presets on top of the built-in ones
+    #[clap(long, global = true)]
+    presets: Option<String>,
+    /// How logs are written to stderr: json lines or text
+    #[clap(long, default_value = "json", possible_values = &["json", "text"], global = true)]
+    log_format: String,
+    /// The OTLP/HTTP collector to export spans to, such as http://localhost:4318
+    #[clap(long, global = true)]
+    otlp: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-const WORKER_COUNT: i32 = 4;
+#[derive(Clap)]
+enum Command {
+    /// Judges a single config without a message queue, printing the result as JSON
+    Judge(JudgeOpts),
+    /// Checks a config, printing every problem found with it
+    Validate(ValidateOpts),
+    /// Prints the JSON Schema of judge configs
+    Schema,
+}
 
-fn init() -> Opts {
+#[derive(Clap)]
+struct JudgeOpts {
+    /// The JSON file of the judge config
+    config: String,
+}
+
+#[derive(Clap)]
+struct ValidateOpts {
+    /// The JSON file of the judge config
+    config: String,
+}
+
+fn init(opts: &Opts) {
+    if let Err(err) = telemetry::init(&opts.log_format, opts.otlp.as_deref()) {
+        eprintln!("failed to initialize telemetry: {}", err);
+        process::exit(1);
+    }
     info!("initializing rayjudge.");
-    env_logger::init();
+}
 
-    let opts: Opts = Opts::parse();
+#[cfg(target_os = "linux")]
+async fn platform_worker(opts: &Opts, workers: usize) -> Result<LinuxWorker, String> {
+    let parallelism = opts.parallel.unwrap_or_else(|| {
+        thread::available_parallelism().map_or(1, |cores| cores.get() / workers)
+    });
+
+    let languages = Arc::new(match &opts.languages {
+        Some(path) => Languages::load(Path::new(path.as_str()))?,
+        None => Languages::builtin(),
+    });
 
-    let worker_queue: ConcurrentQueue<(
-        /* channel */ Channel,
-        /* delivery tag */ LongLongUInt,
-        /* judge config */ JudgeConfig,
-    )> = ConcurrentQueue::unbounded();
+    let presets = Arc::new(match &opts.presets {
+        Some(path) => Presets::load(Path::new(path.as_str()))?,
+        None => Presets::builtin(),
+    });
 
-    if let Err(_) = WORK_QUEUE.set(worker_queue) {
-        panic!("failed to set worker queue for once cell.");
+    let executor: Arc<dyn Executor> = match opts.executor.as_str() {
+        "cri" => {
+            info!(socket = %opts.cri_socket, "connecting to cri runtime.");
+            let runtime = CriRuntime::connect(Path::new(opts.cri_socket.as_str()))
+                .await
+                .map_err(|err| format!("failed to connect to cri runtime: {}", err))?;
+            let version = runtime
+                .version()
+                .await
+                .map_err(|err| format!("failed to get version of cri runtime: {}", err))?;
+            info!(
+                runtime = %version.runtime_name,
+                version = %version.runtime_version,
+                "using cri runtime as executor."
+            );
+            Arc::new(CriExecutor::new(
+                runtime,
+                PathBuf::from(opts.workdir.as_str()),
+                opts.cri_image.clone(),
+            ))
+        }
+        _ => Arc::new(NativeExecutor::new(
+            PathBuf::from(opts.workdir.as_str()),
+            NativeExecutor::prepare_cgroup(PathBuf::from(opts.cgroup.as_str())),
+        )),
+    };
+
+    Ok(LinuxWorker::new(Judge::new(
+        PathBuf::from(opts.data.as_str()),
+        executor,
+        languages,
+        presets,
+        parallelism,
+    )))
+}
+
+#[cfg(target_os = "windows")]
+async fn platform_worker(_opts: &Opts, _workers: usize) -> Result<WindowsWorker, String> {
+    Ok(WindowsWorker::new())
+}
+
+/// Judges the config in the given file and prints its result to stdout.
+async fn judge_once(opts: &Opts, path: &str) -> Result<(), String> {
+    let json =
+        fs::read_to_string(path).map_err(|err| format!("failed to read {}: {}", path, err))?;
+    let config =
+        validate::parse(&json).map_err(|problem| format!("invalid {}: {}", path, problem))?;
+
+    let span = info_span!(
+        "submission",
+        submission = config.id,
+        language = %config.program.language,
+        verdict = field::Empty,
+    );
+    let result = platform_worker(opts, 1)
+        .await?
+        .judge(&config)
+        .instrument(span.clone())
+        .await?;
+    span.record("verdict", result.status.to_string().as_str());
+    println!("{}", serde_json::to_string_pretty(&result).unwrap());
+    Ok(())
+}
+
+/// Reads the number of workers from the given file.
+fn read_workers(path: &str) -> Result<u16, String> {
+    let count = fs::read_to_string(path)
+        .map_err(|err| format!("failed to read {}: {}", path, err))?
+        .trim()
+        .parse()
+        .map_err(|err| format!("invalid number of workers in {}: {}", path, err))?;
+    if count == 0 {
+        return Err(format!("{} has to hold at least one worker", path));
     }
+    Ok(count)
+}
 
-    let count: isize = opts.worker.try_into().unwrap();
-    let worker_semaphore = Semaphore::new(count);
+/// Resizes the pool to the number of workers in the worker file, if any.
+#[cfg(unix)]
+async fn reload<T: PlatformWorker + Sync + Send + Clone + 'static>(
+    pool: &mut Pool<T>,
+    mq: &Queue,
+    worker_file: Option<&str>,
+) {
+    let count = match worker_file.map(read_workers) {
+        Some(Ok(count)) => count,
+        Some(Err(err)) => {
+            error!(workers = pool.size(), "{}, keeping workers.", err);
+            return;
+        }
+        None => {
+            info!(
+                workers = pool.size(),
+                "no worker file to reload, keeping workers."
+            );
+            return;
+        }
+    };
 
-    if let Err(_) = WORKER_SEMAPHORE.set(worker_semaphore) {
-        panic!("failed to set worker semaphore for once cell.");
+    info!(from = pool.size(), to = count, "resizing worker pool.");
+    pool.resize(count as usize);
+    if let Err(err) = mq.set_prefetch(count).await {
+        error!("failed to set prefetch of message queue: {}", err);
     }
+}
 
-    opts
+/// Stops consuming, gives the workers up to `timeout` to finish the requests
+/// they are judging and closes the message queue, returning the exit code.
+#[cfg(unix)]
+async fn shutdown<T: PlatformWorker + Sync + Send + Clone + 'static>(
+    pool: &mut Pool<T>,
+    mq: &Queue,
+    timeout: Duration,
+) -> i32 {
+    mq.cancel().await;
+    let finished = pool.shutdown(timeout).await;
+    if !finished {
+        error!(
+            timeout = timeout.as_secs(),
+            "workers did not finish in time, their judge requests are requeued."
+        );
+    }
+    if let Err(err) = mq.close().await {
+        error!("failed to close message queue: {}", err);
+        return 1;
+    }
+
+    info!("shut down.");
+    if finished {
+        0
+    } else {
+        1
+    }
+}
+
+fn main() {
+    let opts = Opts::parse();
+
+    // only the thread waiting for signals may receive them, so they are
+    // blocked before the exporter and the runtime start threads inheriting
+    // the mask
+    #[cfg(unix)]
+    let blocked = match opts.command {
+        None => signal::block(),
+        Some(_) => Ok(()),
+    };
+    init(&opts);
+    #[cfg(unix)]
+    if let Err(err) = blocked {
+        error!("failed to block signals: {}", err);
+        telemetry::flush();
+        process::exit(1);
+    }
+
+    async_std::task::block_on(run(opts));
+    telemetry::flush();
 }
 
-#[async_std::main]
-async fn main() {
-    let opts = init();
+async fn run(opts: Opts) {
+    match &opts.command {
+        Some(Command::Judge(judge)) => {
+            if let Err(err) = judge_once(&opts, &judge.config).await {
+                error!("{}", err);
+                telemetry::flush();
+                process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Validate(validate)) => {
+            let problems = match fs::read_to_string(&validate.config) {
+                Ok(json) => validate::validate(&json),
+                Err(err) => {
+                    error!(path = %validate.config, "failed to read judge config: {}", err);
+                    process::exit(1);
+                }
+            };
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            process::exit(if problems.is_empty() { 0 } else { 1 });
+        }
+        Some(Command::Schema) => {
+            let schema = schemars::schema_for!(JudgeConfig);
+            println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+            return;
+        }
+        None => (),
+    }
+
+    #[cfg(unix)]
+    let signals = signal::listen();
+
+    if let Some(address) = opts.metrics.clone() {
+        async_std::task::spawn(async move {
+            if let Err(err) = metrics::serve(&address).await {
+                error!(address = %address, "failed to serve metrics: {}", err);
+            }
+        });
+    }
+
+    let workers = match &opts.worker_file {
+        Some(path) => read_workers(path),
+        None if opts.worker == 0 => Err("there has to be at least one worker".to_string()),
+        None => Ok(opts.worker),
+    }
+    .unwrap_or_else(|err| {
+        error!("{}", err);
+        process::exit(1);
+    });
+
+    let platform_worker = platform_worker(&opts, workers as usize)
+        .await
+        .unwrap_or_else(|err| {
+            error!("{}", err);
+            telemetry::flush();
+            process::exit(1);
+        });
 
     info!("connecting to message queue.");
 
-    let mut mq = Queue::new(
+    let mq = Queue::new(
         opts.url,
         opts.queue,
         opts.exchange,
@@ -95,60 +406,74 @@ async fn main() {
             Some(key) => key,
             None => "".to_string(),
         },
+        opts.result_exchange.unwrap_or_default(),
+        opts.result_routing_key,
+        DeadLetter {
+            exchange: opts.dead_letter_exchange,
+            max_attempts: opts.max_attempts,
+            retry_delay: Duration::from_secs(opts.retry_delay),
+        },
     );
 
-    mq.connect().await.unwrap();
-    mq.declare().await.unwrap();
+    #[cfg(unix)]
+    futures::select! {
+        _ = mq.recover().fuse() => (),
+        signal = signal::terminated(&signals).fuse() => {
+            info!(%signal, "received signal while connecting to message queue, exiting.");
+            process::exit(0);
+        }
+    }
+    #[cfg(not(unix))]
+    mq.recover().await;
 
-    info!("starting judge workers.");
+    if MESSAGE_QUEUE.set(mq).is_err() {
+        panic!("failed to set message queue for once cell.");
+    }
+    let mq = MESSAGE_QUEUE.get().unwrap();
 
-    let mut workers = Vec::new();
+    info!(
+        versions = %migration::VERSIONS.join(", "),
+        "starting judge workers."
+    );
 
-    for _ in 0..WORKER_COUNT {
-        WORKER_SEMAPHORE.get().unwrap().acquire();
+    // the broker delivers no more requests than there are workers to take them
+    let (sender, receiver) = channel::bounded(workers as usize);
+    if let Err(err) = mq.set_prefetch(workers).await {
+        error!("failed to set prefetch of message queue: {}", err);
     }
-
-    for i in 0..WORKER_COUNT {
-        #[cfg(target_os = "windows")]
-        let platform_worker = WindowsWorker::new();
-        #[cfg(target_os = "linux")]
-        let platform_worker = LinuxWorker::new();
-        let worker = Worker::new(i, &WORK_QUEUE, &WORKER_SEMAPHORE, platform_worker);
-        mq.subscribe(worker).await.unwrap();
-        workers.push((
-            i,
-            thread::spawn(move || {
-                async_std::task::block_on(worker.worker_thread());
-            }),
-        ));
+    let dispatcher = Dispatcher::new(sender, &MESSAGE_QUEUE);
+    if let Err(err) = mq.subscribe(dispatcher).await {
+        error!(
+            "failed to subscribe to message queue: {}, subscribing once reconnected.",
+            err
+        );
     }
 
-    let config = JudgeConfig {
-        id: 1,
-        version: "v5".to_string(),
-        r#type: "programming".to_string(),
-        stages: Vec::new(),
-        program: Program {
-            language: "csharp".to_string(),
-            compile_args: Vec::new(),
-            sources: Vec::new(),
-            git_repo_name: None,
-            entry_point: None,
-        },
-        random_generator: None,
-        custom_comparator: None,
-        testcases: Vec::new(),
-    };
+    let mut pool = Pool::new(receiver, &MESSAGE_QUEUE, platform_worker, opts.pin_cores);
+    pool.resize(workers as usize);
 
-    let json = serde_json::to_string_pretty(&config).unwrap();
-    mq.publish(json.as_str()).await.unwrap();
+    async_std::task::spawn(mq.supervise());
 
-    for (id, handle) in workers {
-        match handle.join() {
-            Ok(_) => (),
-            Err(_) => {
-                error!("an error occurred in worker {}.", id);
+    #[cfg(unix)]
+    {
+        while let Ok(signal) = signals.recv().await {
+            if signal == Signal::SIGHUP {
+                reload(&mut pool, mq, opts.worker_file.as_deref()).await;
+                continue;
             }
+
+            info!(%signal, "received signal, shutting down.");
+            async_std::task::spawn(async move {
+                signal::terminated(&signals).await;
+                error!("received another signal, exiting without waiting for workers.");
+                process::exit(1);
+            });
+            let timeout = Duration::from_secs(opts.shutdown_timeout);
+            let code = shutdown(&mut pool, mq, timeout).await;
+            telemetry::flush();
+            process::exit(code);
         }
     }
+
+    pool.join();
 }
@@ -0,0 +1,204 @@
+use async_std::{
+    io,
+    net::{TcpListener, TcpStream},
+    prelude::*,
+    task,
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::{
+    sync::atomic::{AtomicI64, Ordering},
+    time::Duration,
+};
+use tracing::{error, info};
+
+/// The upper bounds of the judge latency buckets, in seconds.
+const JUDGE_BUCKETS: &[f64] = &[
+    0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+/// The upper bounds of the sandbox setup time buckets, in seconds.
+const SETUP_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 10.0];
+/// The most of a request read before answering it.
+const MAX_REQUEST: usize = 8192;
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::new();
+}
+
+/// What the judge reports to Prometheus.
+pub struct Metrics {
+    registry: Registry,
+    queue_depth: IntGauge,
+    workers: AtomicI64,
+    /// Workers by whether they are judging
+    states: IntGaugeVec,
+    reconnects: IntCounter,
+    /// Judgements by verdict and language
+    judgements: IntCounterVec,
+    /// Judge latencies by language
+    judge_durations: HistogramVec,
+    setup_durations: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let queue_depth = IntGauge::new(
+            "rayjudge_queue_depth",
+            "Judge requests waiting for a worker.",
+        )
+        .unwrap();
+        let states = IntGaugeVec::new(
+            Opts::new("rayjudge_workers", "Workers by whether they are judging."),
+            &["state"],
+        )
+        .unwrap();
+        let reconnects = IntCounter::new(
+            "rayjudge_amqp_reconnects_total",
+            "Reconnections to the message queue after losing it.",
+        )
+        .unwrap();
+        let judgements = IntCounterVec::new(
+            Opts::new(
+                "rayjudge_judgements_total",
+                "Judgements by verdict and language, error for those that failed.",
+            ),
+            &["verdict", "language"],
+        )
+        .unwrap();
+        let judge_durations = HistogramVec::new(
+            HistogramOpts::new(
+                "rayjudge_judge_duration_seconds",
+                "How long judging a request took, by language.",
+            )
+            .buckets(JUDGE_BUCKETS.to_vec()),
+            &["language"],
+        )
+        .unwrap();
+        let setup_durations = Histogram::with_opts(
+            HistogramOpts::new(
+                "rayjudge_sandbox_setup_seconds",
+                "How long the executor took to set up a sandbox.",
+            )
+            .buckets(SETUP_BUCKETS.to_vec()),
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        // the names are fixed and distinct, so registering cannot fail
+        registry.register(Box::new(queue_depth.clone())).unwrap();
+        registry.register(Box::new(states.clone())).unwrap();
+        registry.register(Box::new(judgements.clone())).unwrap();
+        registry
+            .register(Box::new(judge_durations.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(setup_durations.clone()))
+            .unwrap();
+        registry.register(Box::new(reconnects.clone())).unwrap();
+
+        Self {
+            registry,
+            queue_depth,
+            workers: AtomicI64::new(0),
+            states,
+            reconnects,
+            judgements,
+            judge_durations,
+            setup_durations,
+        }
+    }
+
+    /// Counts requests handed to the workers, or taken by them when negative.
+    pub fn queue(&self, delta: i64) {
+        self.queue_depth.add(delta);
+    }
+
+    pub fn set_workers(&self, count: usize) {
+        self.workers.store(count as i64, Ordering::SeqCst);
+    }
+
+    /// Counts workers starting to judge, or done judging when negative.
+    pub fn busy(&self, delta: i64) {
+        self.states.with_label_values(&["busy"]).add(delta);
+    }
+
+    pub fn reconnected(&self) {
+        self.reconnects.inc();
+    }
+
+    pub fn judged(&self, verdict: &str, language: &str, duration: Duration) {
+        self.judgements
+            .with_label_values(&[verdict, language])
+            .inc();
+        self.judge_durations
+            .with_label_values(&[language])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Records how long an executor took to set up a sandbox.
+    pub fn prepared(&self, duration: Duration) {
+        self.setup_durations.observe(duration.as_secs_f64());
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let busy = self.states.with_label_values(&["busy"]).get();
+        let idle = (self.workers.load(Ordering::SeqCst) - busy).max(0);
+        self.states.with_label_values(&["idle"]).set(idle);
+
+        let mut out = Vec::new();
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut out) {
+            error!("failed to encode metrics: {}", err);
+        }
+        String::from_utf8(out).unwrap_or_default()
+    }
+}
+
+/// Serves the metrics at `/metrics` on the given address.
+pub async fn serve(address: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!(address = %listener.local_addr()?, "serving metrics.");
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                task::spawn(async move {
+                    if let Err(err) = respond(stream).await {
+                        error!("failed to serve metrics: {}", err);
+                    }
+                });
+            }
+            Err(err) => error!("failed to accept metrics connection: {}", err),
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream) -> io::Result<()> {
+    // only the request line matters, the headers are read to be ignored
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut words = request.split_whitespace();
+    let path = words.nth(1).and_then(|target| target.split('?').next());
+    let (status, body) = match (request.starts_with("GET "), path) {
+        (true, Some("/metrics")) => ("200 OK", METRICS.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        TextEncoder::new().format_type(),
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await
+}
@@ -0,0 +1,136 @@
+use crate::schema::VERSION;
+use serde_json::{Map, Value};
+
+/// The versions of `JudgeConfig` this worker judges, oldest first.
+pub const VERSIONS: &[&str] = &[VERSION];
+
+/// Upgrades a config of each version but the current one to the next.
+const MIGRATIONS: &[Migration] = &[];
+
+// every version but the current one needs a migration to the next
+const _: () = assert!(MIGRATIONS.len() == VERSIONS.len() - 1);
+
+type Migration = fn(&mut Map<String, Value>);
+
+/// Upgrades a raw config of any supported version to the current one.
+pub fn migrate(config: &mut Value) -> Result<(), String> {
+    upgrade(config, VERSIONS, MIGRATIONS)
+}
+
+fn upgrade(config: &mut Value, versions: &[&str], migrations: &[Migration]) -> Result<(), String> {
+    let config = config
+        .as_object_mut()
+        .ok_or_else(|| "a judge config has to be an object".to_string())?;
+    let version = match config.get("version") {
+        Some(Value::String(version)) => version.clone(),
+        _ => return Err("the version of the judge config is missing".to_string()),
+    };
+    let position = versions
+        .iter()
+        .position(|supported| *supported == version)
+        .ok_or_else(|| {
+            format!(
+                "unsupported version {}, this worker supports {}",
+                version,
+                versions.join(", ")
+            )
+        })?;
+
+    for (migration, version) in migrations[position..].iter().zip(&versions[position + 1..]) {
+        migration(config);
+        config.insert("version".to_string(), Value::from(*version));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate;
+
+    /// A config from the archive, using presets, scripts and replicas.
+    const V5: &str = include_str!("../tests/fixtures/config-v5.json");
+
+    /// Drops the fields a config leaves out, which are serialized as null.
+    fn strip_nulls(value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                *map = std::mem::take(map)
+                    .into_iter()
+                    .filter(|(_, value)| !value.is_null())
+                    .collect();
+                map.values_mut().for_each(strip_nulls);
+            }
+            Value::Array(values) => values.iter_mut().for_each(strip_nulls),
+            _ => (),
+        }
+    }
+
+    #[test]
+    fn keeps_v5_config() {
+        let problems = validate::validate(V5)
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert!(problems.is_empty(), "{:?}", problems);
+
+        let config = validate::parse(V5).unwrap_or_else(|problem| panic!("{}", problem));
+        let mut judged = serde_json::to_value(&config).unwrap();
+        strip_nulls(&mut judged);
+        assert_eq!(judged, serde_json::from_str::<Value>(V5).unwrap());
+    }
+
+    const TEST_VERSIONS: &[&str] = &["v1", "v2", "v3"];
+    const TEST_MIGRATIONS: &[Migration] = &[
+        |config| {
+            let limit = config.remove("limit").unwrap();
+            config.insert("limits".to_string(), serde_json::json!({ "time": limit }));
+        },
+        |config| {
+            config["limits"]["memory"] = Value::from(256);
+        },
+    ];
+
+    #[test]
+    fn migrates_through_every_later_version() {
+        let mut config = serde_json::json!({ "version": "v1", "limit": 1000 });
+        upgrade(&mut config, TEST_VERSIONS, TEST_MIGRATIONS).unwrap();
+        assert_eq!(
+            config,
+            serde_json::json!({ "version": "v3", "limits": { "time": 1000, "memory": 256 } })
+        );
+
+        let mut config = serde_json::json!({ "version": "v2", "limits": { "time": 500 } });
+        upgrade(&mut config, TEST_VERSIONS, TEST_MIGRATIONS).unwrap();
+        assert_eq!(
+            config,
+            serde_json::json!({ "version": "v3", "limits": { "time": 500, "memory": 256 } })
+        );
+
+        let mut config = serde_json::json!({ "version": "v3", "limits": { "time": 500 } });
+        upgrade(&mut config, TEST_VERSIONS, TEST_MIGRATIONS).unwrap();
+        assert_eq!(
+            config,
+            serde_json::json!({ "version": "v3", "limits": { "time": 500 } })
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_version() {
+        let mut config = serde_json::json!({ "version": "v4" });
+        assert_eq!(
+            migrate(&mut config).unwrap_err(),
+            "unsupported version v4, this worker supports v5"
+        );
+    }
+
+    #[test]
+    fn rejects_missing_version() {
+        let mut config = serde_json::json!({ "id": 1 });
+        assert_eq!(
+            migrate(&mut config).unwrap_err(),
+            "the version of the judge config is missing"
+        );
+    }
+}
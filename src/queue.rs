@@ -1,58 +1,203 @@
+use crate::metrics::METRICS;
 use async_amqp::*;
+use async_std::{
+    channel::{self, Receiver, Sender},
+    future,
+    sync::Mutex,
+    task,
+};
 use async_trait::async_trait;
 use lapin::{
-    options::BasicConsumeOptions, options::BasicPublishOptions, options::ExchangeDeclareOptions,
-    options::QueueBindOptions, options::QueueDeclareOptions, types::FieldTable, Channel,
-    Connection, ConnectionProperties, ConsumerDelegate, ExchangeKind, Result,
+    options::BasicCancelOptions, options::BasicConsumeOptions, options::BasicPublishOptions,
+    options::BasicQosOptions, options::ConfirmSelectOptions, options::ExchangeDeclareOptions,
+    options::QueueBindOptions, options::QueueDeclareOptions, publisher_confirm::Confirmation,
+    types::AMQPValue, types::FieldTable, BasicProperties, Channel, ChannelState, Connection,
+    ConnectionProperties, Consumer, ConsumerDelegate, Error, ExchangeKind, Result,
+};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
 };
-use once_cell::sync::OnceCell;
+use tracing::{error, info, warn};
+
+/// The delay before reconnecting for the first time, doubled after every
+/// failed attempt up to `MAX_BACKOFF`.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How often the channel is checked for being closed, which the broker does
+/// without the connection reporting an error.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The header counting how many times judging a request failed.
+const ATTEMPTS_HEADER: &str = "x-rayjudge-attempts";
+/// The header of a dead-lettered request holding the error it last failed with.
+const ERROR_HEADER: &str = "x-rayjudge-error";
+
+/// Where requests go once judging them failed `max_attempts` times, and
+/// malformed requests right away.
+pub struct DeadLetter {
+    pub exchange: String,
+    pub max_attempts: u32,
+    /// How long a request that failed to be judged waits in the retry queue
+    /// before it is delivered again
+    pub retry_delay: Duration,
+}
 
 pub struct Queue {
     url: String,
     queue: String,
     exchange: String,
     routing_key: String,
-    connection: OnceCell<Connection>,
-    channel: OnceCell<Channel>,
+    result_exchange: String,
+    result_routing_key: Option<String>,
+    dead_letter: DeadLetter,
+    connection: RwLock<Option<Connection>>,
+    channel: RwLock<Option<Channel>>,
+    /// The number of unacknowledged deliveries each consumer holds at most,
+    /// 0 for no limit
+    prefetch: AtomicU16,
+    /// Sets up the consumers subscribed so far on a new channel.
+    subscriptions: Mutex<Vec<Subscription>>,
+    /// The tags of the consumers on the current channel
+    consumers: std::sync::Mutex<Vec<String>>,
+    /// The errors the connection reports once it is lost
+    errors: (Sender<Error>, Receiver<Error>),
+    /// Set once the queue shuts down, after which it no longer recovers
+    closing: AtomicBool,
     consumer_tag: String,
 }
 
+type Subscription = Box<dyn Fn(&Consumer) -> Result<()> + Send + Sync>;
+
 #[async_trait]
 pub trait QueueSubscriber {
-    async fn subscribe<D: ConsumerDelegate + 'static>(&mut self, callback: D) -> Result<()>;
+    async fn subscribe<D>(&self, callback: D) -> Result<()>
+    where
+        D: ConsumerDelegate + Clone + 'static;
 }
 
 #[async_trait]
 pub trait QueuePublisher {
     async fn declare(&self) -> Result<()>;
-    async fn publish(&self, message: &str) -> Result<()>;
+    async fn publish_result(
+        &self,
+        message: &str,
+        reply_to: Option<&str>,
+        correlation_id: Option<&str>,
+    ) -> Result<()>;
+    async fn retry(
+        &self,
+        payload: &[u8],
+        properties: &BasicProperties,
+        error: &str,
+    ) -> Result<bool>;
+    async fn dead_letter(
+        &self,
+        payload: &[u8],
+        properties: &BasicProperties,
+        error: &str,
+    ) -> Result<()>;
 }
 
 #[async_trait]
 impl QueuePublisher for Queue {
-    async fn publish(&self, message: &str) -> Result<()> {
+    /// Replies to the `reply_to` queue of the request if it has one, otherwise
+    /// publishes to the configured result exchange and routing key, if any.
+    async fn publish_result(
+        &self,
+        message: &str,
+        reply_to: Option<&str>,
+        correlation_id: Option<&str>,
+    ) -> Result<()> {
+        let (exchange, routing_key) = match (reply_to, &self.result_routing_key) {
+            (Some(reply_to), _) => ("", reply_to),
+            (None, Some(routing_key)) => (self.result_exchange.as_str(), routing_key.as_str()),
+            (None, None) => return Ok(()),
+        };
+
+        let mut properties =
+            BasicProperties::default().with_content_type("application/json".into());
+        if let Some(correlation_id) = correlation_id {
+            properties = properties.with_correlation_id(correlation_id.into());
+        }
+
         let payload = message.as_bytes().to_vec();
-        self.channel
-            .get()
-            .unwrap()
+        let confirmation = self
+            .channel()?
             .basic_publish(
-                &self.exchange,
-                &self.routing_key,
+                exchange,
+                routing_key,
                 BasicPublishOptions::default(),
                 payload,
-                Default::default(),
+                properties,
             )
+            .await?
             .await?;
 
-        Ok(())
+        confirmed(confirmation)
     }
 
-    async fn declare(&self) -> Result<()> {
-        self.channel
-            .set(self.connection.get().unwrap().create_channel().await?)
-            .unwrap();
+    /// Publishes a request that failed to be judged to the retry queue with
+    /// its attempts counted, or dead-letters it once it has no attempts
+    /// left. Returns whether it was dead-lettered.
+    async fn retry(
+        &self,
+        payload: &[u8],
+        properties: &BasicProperties,
+        error: &str,
+    ) -> Result<bool> {
+        let (properties, exhausted) = count_attempt(properties, self.dead_letter.max_attempts);
+        if exhausted {
+            self.dead_letter(payload, &properties, error).await?;
+            return Ok(true);
+        }
 
-        let channel = self.channel.get().unwrap();
+        let confirmation = self
+            .channel()?
+            .basic_publish(
+                "",
+                &self.retry_queue(),
+                BasicPublishOptions::default(),
+                payload.to_vec(),
+                properties,
+            )
+            .await?
+            .await?;
+
+        confirmed(confirmation).map(|()| false)
+    }
+
+    /// Publishes a request to the dead-letter exchange along with the error
+    /// it failed with.
+    async fn dead_letter(
+        &self,
+        payload: &[u8],
+        properties: &BasicProperties,
+        error: &str,
+    ) -> Result<()> {
+        let mut headers = properties.headers().clone().unwrap_or_default();
+        headers.insert(ERROR_HEADER.into(), AMQPValue::LongString(error.into()));
+        let confirmation = self
+            .channel()?
+            .basic_publish(
+                &self.dead_letter.exchange,
+                "",
+                BasicPublishOptions::default(),
+                payload.to_vec(),
+                properties.clone().with_headers(headers),
+            )
+            .await?
+            .await?;
+
+        confirmed(confirmation)
+    }
+
+    async fn declare(&self) -> Result<()> {
+        let channel = self.channel()?;
 
         channel
             .queue_declare(
@@ -81,55 +226,398 @@ impl QueuePublisher for Queue {
             )
             .await?;
 
+        // requests wait out their TTL here, then the broker dead-letters them
+        // back to the exchange of the queue
+        let mut arguments = FieldTable::default();
+        arguments.insert(
+            "x-message-ttl".into(),
+            AMQPValue::LongUInt(self.dead_letter.retry_delay.as_millis() as u32),
+        );
+        arguments.insert(
+            "x-dead-letter-exchange".into(),
+            AMQPValue::LongString(self.exchange.as_str().into()),
+        );
+        arguments.insert(
+            "x-dead-letter-routing-key".into(),
+            AMQPValue::LongString(self.routing_key.as_str().into()),
+        );
+        channel
+            .queue_declare(
+                &self.retry_queue(),
+                QueueDeclareOptions::default(),
+                arguments,
+            )
+            .await?;
+
+        channel
+            .exchange_declare(
+                &self.dead_letter.exchange,
+                ExchangeKind::Fanout,
+                ExchangeDeclareOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+
+        channel
+            .queue_declare(
+                &self.dead_letter.exchange,
+                QueueDeclareOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+
+        channel
+            .queue_bind(
+                &self.dead_letter.exchange,
+                &self.dead_letter.exchange,
+                "",
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+
+        if !self.result_exchange.is_empty() {
+            channel
+                .exchange_declare(
+                    &self.result_exchange,
+                    ExchangeKind::Direct,
+                    ExchangeDeclareOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?;
+        }
+
         Ok(())
     }
 }
 
 #[async_trait]
 impl QueueSubscriber for Queue {
-    async fn subscribe<D: ConsumerDelegate + 'static>(&mut self, callback: D) -> Result<()> {
-        let consumer = self
-            .channel
-            .get()
-            .unwrap()
-            .basic_consume(
-                &self.queue,
-                self.consumer_tag.as_str(),
-                BasicConsumeOptions::default(),
-                FieldTable::default(),
-            )
-            .await
-            .unwrap();
-        consumer.set_delegate(callback)?;
-
-        Ok(())
+    /// Consumes the queue with the given delegate, again on every channel
+    /// the queue reconnects with.
+    async fn subscribe<D>(&self, callback: D) -> Result<()>
+    where
+        D: ConsumerDelegate + Clone + 'static,
+    {
+        let subscription: Subscription =
+            Box::new(move |consumer| consumer.set_delegate(callback.clone()));
+        let mut subscriptions = self.subscriptions.lock().await;
+        subscriptions.push(subscription);
+        self.consume(subscriptions.last().unwrap()).await
     }
 }
 
 impl Queue {
-    pub fn new(url: String, queue: String, exchange: String, routing_key: String) -> Self {
+    pub fn new(
+        url: String,
+        queue: String,
+        exchange: String,
+        routing_key: String,
+        result_exchange: String,
+        result_routing_key: Option<String>,
+        dead_letter: DeadLetter,
+    ) -> Self {
         Self {
             url,
             queue,
             exchange,
             routing_key,
-            connection: OnceCell::new(),
-            channel: OnceCell::new(),
+            result_exchange,
+            result_routing_key,
+            dead_letter,
+            connection: RwLock::new(None),
+            channel: RwLock::new(None),
+            prefetch: AtomicU16::new(0),
+            subscriptions: Mutex::new(Vec::new()),
+            consumers: std::sync::Mutex::new(Vec::new()),
+            errors: channel::unbounded(),
+            closing: AtomicBool::new(false),
             consumer_tag: "".to_string(),
         }
     }
 
+    /// Opens a new connection and channel, replacing the current ones.
     pub async fn connect(&self) -> Result<()> {
-        self.connection
-            .set(
-                Connection::connect(
-                    self.url.as_str(),
-                    ConnectionProperties::default().with_async_std(),
-                )
-                .await?,
-            )
-            .unwrap();
+        let connection = Connection::connect(
+            self.url.as_str(),
+            ConnectionProperties::default().with_async_std(),
+        )
+        .await?;
+        let errors = self.errors.0.clone();
+        connection.on_error(move |err| {
+            let _ = errors.try_send(err);
+        });
+        let channel = connection.create_channel().await?;
+        // publishes are only settled once the broker confirms them
+        channel
+            .confirm_select(ConfirmSelectOptions::default())
+            .await?;
+
+        *self.channel.write().unwrap() = Some(channel);
+        *self.connection.write().unwrap() = Some(connection);
+        self.consumers.lock().unwrap().clear();
+
+        Ok(())
+    }
+
+    /// Connects, declares the topology and resubscribes every consumer,
+    /// retrying with exponential backoff until it succeeds.
+    pub async fn recover(&self) {
+        let mut backoff = MIN_BACKOFF;
+        while !self.closing.load(Ordering::SeqCst) {
+            match self.reconnect().await {
+                Ok(()) => {
+                    info!("connected to message queue.");
+                    return;
+                }
+                Err(err) => {
+                    error!(
+                        retry_in = backoff.as_secs(),
+                        "failed to connect to message queue: {}, retrying.", err
+                    );
+                    task::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    /// Watches the connection and the channel, recovering once either of them
+    /// is lost.
+    pub async fn supervise(&self) {
+        loop {
+            let lost = future::timeout(CHECK_INTERVAL, self.errors.1.recv()).await;
+            if self.closing.load(Ordering::SeqCst) {
+                return;
+            }
+            // errors reported by connections replaced since are stale
+            if self.connected() {
+                continue;
+            }
+            match lost {
+                Ok(Ok(err)) => warn!("lost connection to message queue: {}.", err),
+                _ => warn!("lost channel of message queue."),
+            }
+            self.recover().await;
+            if !self.closing.load(Ordering::SeqCst) {
+                METRICS.reconnected();
+            }
+        }
+    }
+
+    /// Cancels every consumer for good, so that no further requests are
+    /// delivered while the ones delivered so far are settled.
+    pub async fn cancel(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+        self.subscriptions.lock().await.clear();
+        let consumers: Vec<String> = self.consumers.lock().unwrap().drain(..).collect();
+        let channel = match self.channel() {
+            Ok(channel) => channel,
+            Err(_) => return,
+        };
+        for consumer in consumers {
+            if let Err(err) = channel
+                .basic_cancel(&consumer, BasicCancelOptions::default())
+                .await
+            {
+                error!(consumer = %consumer, "failed to cancel consumer: {}", err);
+            }
+        }
+    }
+
+    /// Closes the channel and the connection for good, upon which the broker
+    /// requeues every delivery left unacknowledged.
+    pub async fn close(&self) -> Result<()> {
+        self.closing.store(true, Ordering::SeqCst);
+        let channel = self.channel.write().unwrap().take();
+        if let Some(channel) = channel.filter(|channel| channel.status().connected()) {
+            channel.close(200, "shutting down").await?;
+        }
+        let connection = self.connection.write().unwrap().take();
+        if let Some(connection) = connection.filter(|connection| connection.status().connected()) {
+            connection.close(200, "shutting down").await?;
+        }
+
+        Ok(())
+    }
+
+    /// Limits the unacknowledged deliveries of each consumer, on the current
+    /// channel and every channel the queue reconnects with.
+    pub async fn set_prefetch(&self, prefetch: u16) -> Result<()> {
+        self.prefetch.store(prefetch, Ordering::SeqCst);
+        self.qos().await?;
+
+        // the limit only applies to consumers started afterwards, so the
+        // current ones are replaced, the deliveries they hold staying on the
+        // channel to be settled
+        let stale: Vec<String> = self.consumers.lock().unwrap().drain(..).collect();
+        for subscription in self.subscriptions.lock().await.iter() {
+            self.consume(subscription).await?;
+        }
+        let channel = self.channel()?;
+        for consumer in stale {
+            channel
+                .basic_cancel(&consumer, BasicCancelOptions::default())
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn qos(&self) -> Result<()> {
+        let prefetch = self.prefetch.load(Ordering::SeqCst);
+        self.channel()?
+            .basic_qos(prefetch, BasicQosOptions { global: false })
+            .await
+    }
+
+    fn connected(&self) -> bool {
+        let connected = self
+            .connection
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|connection| connection.status().connected());
+        connected && self.channel().is_ok()
+    }
+
+    /// The queue failed requests wait in before they are judged again. Its
+    /// TTL is fixed once declared, so changing `retry_delay` takes deleting
+    /// it first.
+    fn retry_queue(&self) -> String {
+        format!("{}.retry", self.queue)
+    }
+
+    /// The current channel, failing if it was closed.
+    fn channel(&self) -> Result<Channel> {
+        match &*self.channel.read().unwrap() {
+            Some(channel) if channel.status().connected() => Ok(channel.clone()),
+            Some(channel) => Err(Error::InvalidChannelState(channel.status().state())),
+            None => Err(Error::InvalidChannelState(ChannelState::Closed)),
+        }
+    }
+
+    async fn reconnect(&self) -> Result<()> {
+        self.connect().await?;
+        self.declare().await?;
+        self.qos().await?;
+        for subscription in self.subscriptions.lock().await.iter() {
+            self.consume(subscription).await?;
+        }
 
         Ok(())
     }
+
+    async fn consume(&self, subscription: &Subscription) -> Result<()> {
+        let consumer = self
+            .channel()?
+            .basic_consume(
+                &self.queue,
+                self.consumer_tag.as_str(),
+                BasicConsumeOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        self.consumers
+            .lock()
+            .unwrap()
+            .push(consumer.tag().to_string());
+        subscription(&consumer)
+    }
+}
+
+/// Fails a publish the broker did not confirm.
+fn confirmed(confirmation: Confirmation) -> Result<()> {
+    match confirmation {
+        Confirmation::Nack(_) => Err(Error::IOError(Arc::new(io::Error::other(
+            "the broker failed to take the message",
+        )))),
+        Confirmation::Ack(_) | Confirmation::NotRequested => Ok(()),
+    }
+}
+
+/// Counts another failed attempt in the headers of a request, telling
+/// whether it has no attempts left.
+fn count_attempt(properties: &BasicProperties, max_attempts: u32) -> (BasicProperties, bool) {
+    let attempts = attempts(properties) + 1;
+    let mut headers = properties.headers().clone().unwrap_or_default();
+    headers.insert(ATTEMPTS_HEADER.into(), AMQPValue::LongUInt(attempts));
+    (
+        properties.clone().with_headers(headers),
+        attempts >= max_attempts,
+    )
+}
+
+/// How many times judging a request failed so far.
+fn attempts(properties: &BasicProperties) -> u32 {
+    let attempts = properties
+        .headers()
+        .as_ref()
+        .and_then(|headers| headers.inner().get(ATTEMPTS_HEADER).cloned());
+    match attempts {
+        Some(AMQPValue::LongUInt(attempts)) => attempts,
+        Some(AMQPValue::LongInt(attempts)) => attempts.max(0) as u32,
+        Some(AMQPValue::LongLongInt(attempts)) => attempts.max(0) as u32,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_attempts(attempts: AMQPValue) -> BasicProperties {
+        let mut headers = FieldTable::default();
+        headers.insert(ATTEMPTS_HEADER.into(), attempts);
+        BasicProperties::default().with_headers(headers)
+    }
+
+    #[test]
+    fn reads_attempts() {
+        assert_eq!(attempts(&BasicProperties::default()), 0);
+        assert_eq!(attempts(&with_attempts(AMQPValue::LongUInt(2))), 2);
+        assert_eq!(attempts(&with_attempts(AMQPValue::LongInt(3))), 3);
+        assert_eq!(attempts(&with_attempts(AMQPValue::LongLongInt(4))), 4);
+        // other publishers may send signed or malformed counts
+        assert_eq!(attempts(&with_attempts(AMQPValue::LongInt(-1))), 0);
+        assert_eq!(
+            attempts(&with_attempts(AMQPValue::LongString("2".into()))),
+            0
+        );
+        assert_eq!(attempts(&with_attempts(AMQPValue::Boolean(true))), 0);
+    }
+
+    #[test]
+    fn dead_letters_at_the_last_attempt() {
+        let (properties, exhausted) = count_attempt(&BasicProperties::default(), 3);
+        assert!(!exhausted);
+        assert_eq!(attempts(&properties), 1);
+
+        let (properties, exhausted) = count_attempt(&properties, 3);
+        assert!(!exhausted);
+        assert_eq!(attempts(&properties), 2);
+
+        let (properties, exhausted) = count_attempt(&properties, 3);
+        assert!(exhausted);
+        assert_eq!(attempts(&properties), 3);
+
+        assert!(count_attempt(&BasicProperties::default(), 1).1);
+        assert!(count_attempt(&with_attempts(AMQPValue::LongUInt(7)), 3).1);
+    }
+
+    #[test]
+    fn keeps_other_headers() {
+        let mut properties = with_attempts(AMQPValue::LongUInt(1));
+        let mut headers = properties.headers().clone().unwrap();
+        headers.insert("x-other".into(), AMQPValue::LongUInt(9));
+        properties = properties.with_headers(headers);
+
+        let (properties, _) = count_attempt(&properties, 3);
+        let headers = properties.headers().clone().unwrap();
+        assert_eq!(
+            headers.inner().get("x-other"),
+            Some(&AMQPValue::LongUInt(9))
+        );
+        assert_eq!(attempts(&properties), 2);
+    }
 }
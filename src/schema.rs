@@ -1,20 +1,26 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 
-#[derive(Serialize, Deserialize)]
+/// The version of `JudgeConfig` this worker judges.
+pub const VERSION: &str = "v5";
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct TestcaseEntry {
     pub id: i32,
     pub is_random: Option<bool>,
+    /// The seed to generate a random testcase with, a fresh one is drawn if absent
+    pub seed: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Testcase {
     pub id: i32,
     pub sources: Vec<File>,
     pub hidden: Option<bool>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct File {
     pub path: String,
     pub locked: Option<bool>,
@@ -22,7 +28,7 @@ pub struct File {
     pub r#type: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Program {
     pub language: String,
     pub compile_args: Vec<String>,
@@ -31,40 +37,62 @@ pub struct Program {
     pub entry_point: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Require {
     pub on: String,
     pub cond: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Script {
     pub check: Option<String>,
     pub run: Option<String>,
     pub compare: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Limits {
+    /// CPU time in milliseconds
     pub time: Option<i64>,
+    /// Memory in bytes
     pub memory: Option<i64>,
+    /// Size of any written file in bytes
     pub file: Option<i64>,
+    /// Number of processes and threads
     pub proc: Option<i64>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Stage {
     pub name: String,
     pub preset: Option<String>,
     pub require: Option<Require>,
     pub script: Option<Script>,
     pub limits: Option<Limits>,
+    /// Limits of the interactor, the ones of the stage if missing
+    pub interactor_limits: Option<Limits>,
     pub testcase: Option<TestcaseEntry>,
     pub grade: i32,
     pub replicas: Option<Vec<Stage>>,
+    /// How the scores of the replicas make up the score of the stage
+    pub aggregate: Option<Aggregate>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default, JsonSchema)]
+pub enum Aggregate {
+    /// The sum of the replica scores
+    #[default]
+    #[serde(rename = "sum")]
+    Sum,
+    /// The lowest replica score
+    #[serde(rename = "min")]
+    Min,
+    /// The grade of the stage if every replica was accepted, zero otherwise
+    #[serde(rename = "all")]
+    All,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct JudgeConfig {
     pub id: i32,
     pub version: String,
@@ -72,14 +100,194 @@ pub struct JudgeConfig {
     pub stages: Vec<Stage>,
     pub program: Program,
     pub random_generator: Option<Program>,
+    /// Produces the expected output of random testcases
+    pub reference_program: Option<Program>,
     pub custom_comparator: Option<Program>,
+    /// Talks to the program in stages whose `Script.run` is `interactive`
+    pub interactor: Option<Program>,
     pub testcases: Vec<Testcase>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Verdict {
+    #[serde(rename = "AC")]
+    Accepted,
+    #[serde(rename = "WA")]
+    WrongAnswer,
+    #[serde(rename = "TLE")]
+    TimeLimitExceeded,
+    #[serde(rename = "MLE")]
+    MemoryLimitExceeded,
+    #[serde(rename = "RE")]
+    RuntimeError,
+    #[serde(rename = "CE")]
+    CompileError,
+    #[serde(rename = "OLE")]
+    OutputLimitExceeded,
+    #[serde(rename = "SE")]
+    SystemError,
+    #[serde(rename = "PE")]
+    PresentationError,
+    #[serde(rename = "PLE")]
+    ProcessLimitExceeded,
+    #[serde(rename = "SKIP")]
+    Skipped,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StageResult {
+    pub name: String,
+    pub testcase: Option<i32>,
+    /// The seed a random testcase was generated with
+    pub seed: Option<u64>,
+    pub status: Verdict,
+    pub score: i32,
+    /// CPU time in milliseconds
+    pub time: u64,
+    /// Wall clock time in milliseconds
+    pub wall_time: u64,
+    /// Peak memory in bytes
+    pub memory: u64,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    pub message: Option<String>,
+    pub replicas: Option<Vec<StageResult>>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct JudgeResult {
     pub id: i32,
-    pub status: String,
+    pub status: Verdict,
+    pub score: i32,
+    pub message: Option<String>,
+    pub stages: Vec<StageResult>,
+}
+
+impl StageResult {
+    pub fn accepted(stage: &Stage) -> Self {
+        Self {
+            name: stage.name.clone(),
+            testcase: stage.testcase.as_ref().map(|testcase| testcase.id),
+            seed: None,
+            status: Verdict::Accepted,
+            score: stage.grade,
+            time: 0,
+            wall_time: 0,
+            memory: 0,
+            exit_code: None,
+            signal: None,
+            stdout: None,
+            stderr: None,
+            message: None,
+            replicas: None,
+        }
+    }
+
+    pub fn system_error(stage: &Stage, message: String) -> Self {
+        Self {
+            status: Verdict::SystemError,
+            score: 0,
+            message: Some(message),
+            ..Self::accepted(stage)
+        }
+    }
+
+    pub fn skipped(stage: &Stage, message: String) -> Self {
+        Self {
+            status: Verdict::Skipped,
+            ..Self::system_error(stage, message)
+        }
+    }
+
+    /// Combines the results of the replicas of a stage, the first replica
+    /// that was not accepted deciding the verdict.
+    pub fn from_replicas(stage: &Stage, replicas: Vec<StageResult>) -> Self {
+        let status = replicas
+            .iter()
+            .map(|replica| replica.status)
+            .find(|status| *status != Verdict::Accepted)
+            .unwrap_or(Verdict::Accepted);
+        let score = match stage.aggregate.unwrap_or_default() {
+            Aggregate::Sum => replicas
+                .iter()
+                .map(|replica| replica.score)
+                .sum::<i32>()
+                .clamp(0, stage.grade.max(0)),
+            Aggregate::Min => replicas
+                .iter()
+                .map(|replica| replica.score)
+                .min()
+                .unwrap_or(0),
+            Aggregate::All if status == Verdict::Accepted => stage.grade,
+            Aggregate::All => 0,
+        };
+
+        Self {
+            name: stage.name.clone(),
+            testcase: stage.testcase.as_ref().map(|testcase| testcase.id),
+            seed: None,
+            status,
+            score,
+            time: replicas
+                .iter()
+                .map(|replica| replica.time)
+                .max()
+                .unwrap_or(0),
+            wall_time: replicas
+                .iter()
+                .map(|replica| replica.wall_time)
+                .max()
+                .unwrap_or(0),
+            memory: replicas
+                .iter()
+                .map(|replica| replica.memory)
+                .max()
+                .unwrap_or(0),
+            exit_code: None,
+            signal: None,
+            stdout: None,
+            stderr: None,
+            message: None,
+            replicas: Some(replicas),
+        }
+    }
+}
+
+impl JudgeResult {
+    pub fn compile_error(id: i32, message: Option<String>) -> Self {
+        Self {
+            id,
+            status: Verdict::CompileError,
+            score: 0,
+            message,
+            stages: Vec::new(),
+        }
+    }
+
+    pub fn system_error(id: i32, message: String) -> Self {
+        Self {
+            status: Verdict::SystemError,
+            ..Self::compile_error(id, Some(message))
+        }
+    }
+
+    /// Sums up the stage scores, the first stage that was neither accepted
+    /// nor skipped decides the overall verdict.
+    pub fn from_stages(id: i32, stages: Vec<StageResult>) -> Self {
+        Self {
+            id,
+            status: stages
+                .iter()
+                .map(|stage| stage.status)
+                .find(|status| *status != Verdict::Accepted && *status != Verdict::Skipped)
+                .unwrap_or(Verdict::Accepted),
+            score: stages.iter().map(|stage| stage.score).sum(),
+            message: None,
+            stages,
+        }
+    }
 }
 
 impl Display for JudgeConfig {
@@ -92,6 +300,15 @@ impl Display for JudgeConfig {
     }
 }
 
+impl Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(s)) => f.write_str(s.as_str()),
+            _ => Err(std::fmt::Error {}),
+        }
+    }
+}
+
 impl Display for JudgeResult {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let json = serde_json::to_string_pretty(self);
@@ -101,3 +318,72 @@ impl Display for JudgeResult {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stage(aggregate: Aggregate, grade: i32) -> Stage {
+        serde_json::from_value(serde_json::json!({
+            "name": "s1",
+            "grade": grade,
+            "aggregate": aggregate,
+        }))
+        .unwrap()
+    }
+
+    fn replica(status: Verdict, score: i32) -> StageResult {
+        StageResult {
+            status,
+            score,
+            ..StageResult::accepted(&stage(Aggregate::Sum, score))
+        }
+    }
+
+    fn aggregate(aggregate: Aggregate, grade: i32, replicas: Vec<StageResult>) -> (Verdict, i32) {
+        let result = StageResult::from_replicas(&stage(aggregate, grade), replicas);
+        (result.status, result.score)
+    }
+
+    #[test]
+    fn sums_scores_up_to_the_grade() {
+        let replicas = || {
+            vec![
+                replica(Verdict::Accepted, 6),
+                replica(Verdict::WrongAnswer, 3),
+            ]
+        };
+        assert_eq!(
+            aggregate(Aggregate::Sum, 10, replicas()),
+            (Verdict::WrongAnswer, 9)
+        );
+        assert_eq!(
+            aggregate(Aggregate::Sum, 5, replicas()),
+            (Verdict::WrongAnswer, 5)
+        );
+        assert_eq!(
+            aggregate(Aggregate::Sum, 0, replicas()),
+            (Verdict::WrongAnswer, 0)
+        );
+    }
+
+    #[test]
+    fn scores_the_worst_or_every_replica() {
+        let replicas = || vec![replica(Verdict::Accepted, 6), replica(Verdict::Accepted, 3)];
+        assert_eq!(
+            aggregate(Aggregate::Min, 10, replicas()),
+            (Verdict::Accepted, 3)
+        );
+        assert_eq!(
+            aggregate(Aggregate::All, 10, replicas()),
+            (Verdict::Accepted, 10)
+        );
+
+        let mut replicas = replicas();
+        replicas.push(replica(Verdict::TimeLimitExceeded, 0));
+        assert_eq!(
+            aggregate(Aggregate::All, 10, replicas),
+            (Verdict::TimeLimitExceeded, 0)
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use async_std::{
+    channel::{self, Receiver},
+    future,
+};
+use nix::sys::signal::{SigSet, Signal};
+use std::thread;
+use tracing::error;
+
+/// Blocks the signals the judge handles in the calling thread, and in every
+/// thread it starts from then on, leaving them to `listen`.
+pub fn block() -> nix::Result<()> {
+    handled().thread_block()
+}
+
+/// Receives the handled signals on a thread of its own, forwarding them to
+/// the returned receiver.
+pub fn listen() -> Receiver<Signal> {
+    let (sender, receiver) = channel::unbounded();
+    thread::spawn(move || loop {
+        match handled().wait() {
+            Ok(signal) => {
+                if sender.try_send(signal).is_err() {
+                    return;
+                }
+            }
+            Err(err) => {
+                error!("failed to wait for signals: {}", err);
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// Waits for a signal asking the judge to shut down, skipping the others.
+pub async fn terminated(signals: &Receiver<Signal>) -> Signal {
+    loop {
+        match signals.recv().await {
+            Ok(signal) if signal == Signal::SIGINT || signal == Signal::SIGTERM => return signal,
+            Ok(_) => (),
+            Err(_) => future::pending().await,
+        }
+    }
+}
+
+fn handled() -> SigSet {
+    let mut set = SigSet::empty();
+    set.add(Signal::SIGHUP);
+    set.add(Signal::SIGINT);
+    set.add(Signal::SIGTERM);
+    set
+}
@@ -0,0 +1,96 @@
+use once_cell::sync::OnceCell;
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+use std::{env, io};
+use tracing::{error, level_filters::LevelFilter};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+/// The path spans are posted to when the OTLP endpoint has none.
+const TRACES_PATH: &str = "/v1/traces";
+
+lazy_static! {
+    static ref PROVIDER: OnceCell<TracerProvider> = OnceCell::new();
+}
+
+/// Writes events, along with the spans they happened in, to stderr as JSON
+/// lines or text, and exports spans to an OTLP/HTTP collector if there is
+/// one. Records of the `log` crate are written the same way.
+///
+/// Which events are written is read from `RUST_LOG`, an invalid directive
+/// failing startup. Spans are exported whatever it says, since they give the
+/// context of the events that are written.
+pub fn init(format: &str, otlp: Option<&str>) -> Result<(), String> {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::ERROR.into())
+        .parse(env::var("RUST_LOG").unwrap_or_default())
+        .map_err(|err| format!("invalid RUST_LOG: {}", err))?;
+    let fmt = match format {
+        "text" => tracing_subscriber::fmt::layer()
+            .with_writer(io::stderr)
+            .with_ansi(false)
+            .boxed(),
+        _ => tracing_subscriber::fmt::layer()
+            .json()
+            .with_span_list(true)
+            .with_writer(io::stderr)
+            .boxed(),
+    };
+
+    let otel = match otlp {
+        Some(url) => {
+            let exporter = SpanExporter::builder()
+                .with_http()
+                .with_protocol(Protocol::HttpJson)
+                .with_endpoint(traces_url(url)?)
+                .build()
+                .map_err(|err| format!("failed to create the OTLP exporter: {}", err))?;
+            let provider = TracerProvider::builder()
+                .with_batch_exporter(exporter, runtime::AsyncStd)
+                .with_resource(Resource::new([KeyValue::new("service.name", "rayjudge")]))
+                .build();
+            let tracer = provider.tracer("rayjudge");
+            let _ = PROVIDER.set(provider);
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(fmt.with_filter(filter))
+        .with(otel)
+        .try_init()
+        .map_err(|err| err.to_string())
+}
+
+/// Waits for the spans closed so far to be exported.
+pub fn flush() {
+    if let Some(provider) = PROVIDER.get() {
+        for result in provider.force_flush() {
+            if let Err(err) = result {
+                error!("failed to export spans: {}", err);
+            }
+        }
+    }
+}
+
+/// Where an OTLP/HTTP collector such as `http://localhost:4318` takes spans.
+fn traces_url(url: &str) -> Result<String, String> {
+    let uri: http::Uri = url
+        .parse()
+        .map_err(|err| format!("invalid OTLP endpoint {}: {}", url, err))?;
+    if uri.scheme_str() != Some("http") {
+        return Err(format!(
+            "unsupported OTLP endpoint {}, expected http://",
+            url
+        ));
+    }
+    if uri.host().is_none() {
+        return Err(format!("the OTLP endpoint {} has no host", url));
+    }
+
+    Ok(match uri.path() {
+        "" | "/" => format!("{}{}", url.trim_end_matches('/'), TRACES_PATH),
+        _ => url.to_string(),
+    })
+}
@@ -0,0 +1,402 @@
+use crate::{
+    migration,
+    schema::{JudgeConfig, Limits, Stage, VERSION},
+};
+#[cfg(unix)]
+use crate::{
+    schema::Script,
+    worker::{plan::Plan, script},
+};
+use serde_json::Value;
+use std::{collections::HashMap, fmt::Display};
+
+/// A problem with a judge config, along with the path of the offending value
+/// such as `stages[1].limits.time`.
+pub struct Problem {
+    pub path: String,
+    pub message: String,
+}
+
+impl Problem {
+    pub fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() || self.path == "." {
+            f.write_str(&self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// Why a judge request cannot be judged.
+pub enum Rejection {
+    /// A request of a version this worker does not judge, which the
+    /// requester is told about in a result
+    Unsupported(i32, String),
+    Malformed(Problem),
+}
+
+/// Parses a judge request of any supported version, migrating it to the
+/// current one.
+pub fn parse_request(json: &str) -> Result<JudgeConfig, Rejection> {
+    let mut config: Value = serde_json::from_str(json)
+        .map_err(|err| Rejection::Malformed(Problem::new("", err.to_string())))?;
+    if let Err(err) = migration::migrate(&mut config) {
+        return Err(match config.get("id").and_then(Value::as_i64) {
+            Some(id) => Rejection::Unsupported(id as i32, err),
+            None => Rejection::Malformed(Problem::new("version", err)),
+        });
+    }
+
+    serde_path_to_error::deserialize(config).map_err(|err| {
+        Rejection::Malformed(Problem::new(
+            err.path().to_string(),
+            err.inner().to_string(),
+        ))
+    })
+}
+
+/// Parses a judge config, locating the value it failed on.
+pub fn parse(json: &str) -> Result<JudgeConfig, Problem> {
+    parse_request(json).map_err(|rejection| match rejection {
+        Rejection::Unsupported(_, message) => Problem::new("version", message),
+        Rejection::Malformed(problem) => problem,
+    })
+}
+
+/// Reports every problem with a judge config: an unsupported version,
+/// duplicate stage names and testcase ids, dangling `require.on`, unknown
+/// testcases and negative limits, and finally whatever keeps its stages
+/// from being planned.
+pub fn validate(json: &str) -> Vec<Problem> {
+    let config = match parse(json) {
+        Ok(config) => config,
+        Err(problem) => return vec![problem],
+    };
+
+    #[allow(unused_mut)]
+    let mut problems = check(&config);
+    #[cfg(unix)]
+    if problems.is_empty() {
+        if let Err(err) = Plan::new(&config.stages) {
+            problems.push(Problem::new("stages", err));
+        }
+    }
+
+    problems
+}
+
+/// Checks a parsed judge config for the problems serde cannot tell.
+pub fn check(config: &JudgeConfig) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    if config.version != VERSION {
+        problems.push(Problem::new(
+            "version",
+            format!(
+                "unsupported version {}, expected {}",
+                config.version, VERSION
+            ),
+        ));
+    }
+
+    let mut testcases = HashMap::new();
+    for (index, testcase) in config.testcases.iter().enumerate() {
+        if let Some(first) = testcases.insert(testcase.id, index) {
+            problems.push(Problem::new(
+                format!("testcases[{}].id", index),
+                format!(
+                    "testcase {} is already defined by testcases[{}]",
+                    testcase.id, first
+                ),
+            ));
+        }
+    }
+
+    let mut names = HashMap::new();
+    for (index, stage) in config.stages.iter().enumerate() {
+        if let Some(first) = names.insert(stage.name.as_str(), index) {
+            problems.push(Problem::new(
+                format!("stages[{}].name", index),
+                format!(
+                    "stage {} is already defined by stages[{}]",
+                    stage.name, first
+                ),
+            ));
+        }
+    }
+
+    for (index, stage) in config.stages.iter().enumerate() {
+        let path = format!("stages[{}]", index);
+        if let Some(require) = &stage.require {
+            if !names.contains_key(require.on.as_str()) {
+                problems.push(Problem::new(
+                    format!("{}.require.on", path),
+                    format!("unknown stage {}", require.on),
+                ));
+            }
+        }
+        check_stage(&mut problems, &path, stage, &testcases);
+        #[cfg(unix)]
+        check_answer(&mut problems, &path, config, stage, None);
+        for (replica_index, replica) in stage.replicas.iter().flatten().enumerate() {
+            let path = format!("{}.replicas[{}]", path, replica_index);
+            check_stage(&mut problems, &path, replica, &testcases);
+            #[cfg(unix)]
+            check_answer(&mut problems, &path, config, replica, Some(stage));
+        }
+    }
+
+    problems
+}
+
+fn check_stage(
+    problems: &mut Vec<Problem>,
+    path: &str,
+    stage: &Stage,
+    testcases: &HashMap<i32, usize>,
+) {
+    if let Some(entry) = &stage.testcase {
+        if entry.is_random != Some(true) && !testcases.contains_key(&entry.id) {
+            problems.push(Problem::new(
+                format!("{}.testcase.id", path),
+                format!("testcase {} does not exist", entry.id),
+            ));
+        }
+    }
+    for (name, limits) in [
+        ("limits", &stage.limits),
+        ("interactor_limits", &stage.interactor_limits),
+    ]
+    .iter()
+    {
+        if let Some(limits) = limits {
+            check_limits(problems, &format!("{}.{}", path, name), limits);
+        }
+    }
+}
+
+/// Flags a batch stage on a random testcase that compares its output with a
+/// built-in comparator, since only the reference program produces an answer
+/// for it. Stages using a preset are left alone, which may judge the output
+/// in ways not known here.
+#[cfg(unix)]
+fn check_answer(
+    problems: &mut Vec<Problem>,
+    path: &str,
+    config: &JudgeConfig,
+    stage: &Stage,
+    parent: Option<&Stage>,
+) {
+    let random = stage
+        .testcase
+        .as_ref()
+        .is_some_and(|entry| entry.is_random == Some(true));
+    let preset = stage.preset.is_some() || parent.is_some_and(|parent| parent.preset.is_some());
+    if !random || preset || config.reference_program.is_some() {
+        return;
+    }
+
+    let script = |field: fn(&Script) -> &Option<String>| {
+        stage
+            .script
+            .as_ref()
+            .and_then(|script| field(script).as_deref())
+            .or_else(|| {
+                parent
+                    .and_then(|parent| parent.script.as_ref())
+                    .and_then(|script| field(script).as_deref())
+            })
+    };
+    let compare = script(|script| &script.compare);
+    let judged = !matches!(script(|script| &script.run), None | Some("batch"))
+        || compare.is_some_and(script::is_script)
+        || (matches!(compare, None | Some("custom")) && config.custom_comparator.is_some())
+        || script(|script| &script.check).is_some_and(script::is_script);
+    if !judged {
+        problems.push(Problem::new(
+            format!("{}.testcase", path),
+            "a random testcase needs a reference program or a comparator to judge the output",
+        ));
+    }
+}
+
+fn check_limits(problems: &mut Vec<Problem>, path: &str, limits: &Limits) {
+    let values = [
+        ("time", limits.time),
+        ("memory", limits.memory),
+        ("file", limits.file),
+        ("proc", limits.proc),
+    ];
+    for (name, value) in values.iter() {
+        if let Some(value) = value {
+            if *value < 0 {
+                problems.push(Problem::new(
+                    format!("{}.{}", path, name),
+                    format!("{} must not be negative", value),
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A config with a single stage on a single testcase.
+    fn minimal() -> Value {
+        json!({
+            "id": 1,
+            "version": VERSION,
+            "type": "programming",
+            "program": { "language": "c", "compile_args": [], "sources": [{ "path": "a.c" }] },
+            "stages": [{ "name": "s1", "grade": 10, "testcase": { "id": 1 } }],
+            "testcases": [{ "id": 1, "sources": [{ "path": "1.in", "type": "input" }] }]
+        })
+    }
+
+    fn problems(config: &Value) -> Vec<(String, String)> {
+        validate(&config.to_string())
+            .into_iter()
+            .map(|problem| (problem.path, problem.message))
+            .collect()
+    }
+
+    fn problem(path: &str, message: &str) -> (String, String) {
+        (path.to_string(), message.to_string())
+    }
+
+    #[test]
+    fn accepts_valid_config() {
+        assert_eq!(problems(&minimal()), vec![]);
+    }
+
+    #[test]
+    fn reports_duplicate_testcase_ids() {
+        let mut config = minimal();
+        let testcase = config["testcases"][0].clone();
+        config["testcases"].as_array_mut().unwrap().push(testcase);
+        assert_eq!(
+            problems(&config),
+            vec![problem(
+                "testcases[1].id",
+                "testcase 1 is already defined by testcases[0]"
+            )]
+        );
+    }
+
+    #[test]
+    fn reports_duplicate_stage_names() {
+        let mut config = minimal();
+        let stage = config["stages"][0].clone();
+        config["stages"].as_array_mut().unwrap().push(stage);
+        assert_eq!(
+            problems(&config),
+            vec![problem(
+                "stages[1].name",
+                "stage s1 is already defined by stages[0]"
+            )]
+        );
+    }
+
+    #[test]
+    fn reports_dangling_require() {
+        let mut config = minimal();
+        config["stages"][0]["require"] = json!({ "on": "s0" });
+        assert_eq!(
+            problems(&config),
+            vec![problem("stages[0].require.on", "unknown stage s0")]
+        );
+    }
+
+    #[test]
+    fn reports_unknown_testcases() {
+        let mut config = minimal();
+        config["stages"][0]["testcase"] = json!({ "id": 2 });
+        config["stages"][0]["replicas"] = json!([
+            { "name": "r1", "grade": 5, "testcase": { "id": 1 } },
+            { "name": "r2", "grade": 5, "testcase": { "id": 3 } }
+        ]);
+        assert_eq!(
+            problems(&config),
+            vec![
+                problem("stages[0].testcase.id", "testcase 2 does not exist"),
+                problem(
+                    "stages[0].replicas[1].testcase.id",
+                    "testcase 3 does not exist"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_negative_limits() {
+        let mut config = minimal();
+        config["stages"][0]["limits"] = json!({ "time": -1, "memory": 1024 });
+        config["stages"][0]["interactor_limits"] = json!({ "proc": -2 });
+        assert_eq!(
+            problems(&config),
+            vec![
+                problem("stages[0].limits.time", "-1 must not be negative"),
+                problem(
+                    "stages[0].interactor_limits.proc",
+                    "-2 must not be negative"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn locates_malformed_values() {
+        let mut config = minimal();
+        config["stages"][0]["limits"] = json!({ "time": "1s" });
+        assert_eq!(
+            problems(&config),
+            vec![problem(
+                "stages[0].limits.time",
+                "invalid type: string \"1s\", expected i64"
+            )]
+        );
+
+        let mut config = minimal();
+        config["testcases"][0]["sources"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("path");
+        assert_eq!(
+            problems(&config),
+            vec![problem("testcases[0].sources[0]", "missing field `path`")]
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_requests() {
+        let mut config = minimal();
+        config["version"] = json!("v4");
+        match parse_request(&config.to_string()) {
+            Err(Rejection::Unsupported(id, message)) => {
+                assert_eq!(id, 1);
+                assert_eq!(message, "unsupported version v4, this worker supports v5");
+            }
+            _ => panic!("a v4 request was not rejected as unsupported"),
+        }
+
+        config.as_object_mut().unwrap().remove("id");
+        assert_eq!(
+            problems(&config),
+            vec![problem(
+                "version",
+                "unsupported version v4, this worker supports v5"
+            )]
+        );
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    mem,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+const ROOT: &str = "/sys/fs/cgroup";
+const CONTROLLERS: &[&str] = &["memory", "pids"];
+
+/// A cgroup v2 subtree owned by a single sandboxed run, or the cgroup of a
+/// container, which is left to its runtime.
+pub struct Cgroup {
+    path: PathBuf,
+    owned: bool,
+}
+
+/// The peak memory usage of a cgroup since it was reset.
+pub struct MemoryPeak(File);
+
+impl MemoryPeak {
+    pub fn read(&mut self) -> Option<u64> {
+        let mut peak = String::new();
+        self.0.seek(SeekFrom::Start(0)).ok()?;
+        self.0.read_to_string(&mut peak).ok()?;
+        peak.trim().parse().ok()
+    }
+}
+
+impl Cgroup {
+    /// Creates the delegated parent cgroup and makes sure that the memory
+    /// and pids controllers are available to the cgroups of every run.
+    ///
+    /// Nothing is created or written unless the parent lies on a cgroup2
+    /// filesystem, since on a cgroup v1 host it would only make stray files.
+    pub fn prepare(parent: &Path) -> io::Result<()> {
+        if !on_cgroup2(parent)? {
+            return Err(io::Error::other(format!(
+                "{} is not on a cgroup2 filesystem",
+                parent.display()
+            )));
+        }
+        fs::create_dir_all(parent)?;
+        let enable = CONTROLLERS
+            .iter()
+            .map(|controller| format!("+{}", controller))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if let Some(grandparent) = parent.parent() {
+            // the grandparent may be the mount point of the hierarchy, in a
+            // filesystem of its own
+            if on_cgroup2(grandparent)? {
+                let _ = fs::write(grandparent.join("cgroup.subtree_control"), &enable);
+            }
+        }
+        let _ = fs::write(parent.join("cgroup.subtree_control"), &enable);
+
+        let enabled = fs::read_to_string(parent.join("cgroup.subtree_control"))?;
+        for controller in CONTROLLERS {
+            if !enabled.split_whitespace().any(|c| c == *controller) {
+                return Err(io::Error::other(format!(
+                    "controller {} is not available in {}",
+                    controller,
+                    parent.display()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn new(parent: &Path, name: &str) -> io::Result<Self> {
+        let path = parent.join(name);
+        fs::create_dir(&path)?;
+
+        Ok(Self { path, owned: true })
+    }
+
+    /// Looks up the cgroup v2 a process, such as the init of a container,
+    /// runs in.
+    pub fn of_process(pid: libc::pid_t) -> io::Result<Self> {
+        let path = fs::read_to_string(format!("/proc/{}/cgroup", pid))?
+            .lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .map(|path| Path::new(ROOT).join(path.trim_start_matches('/')))
+            .ok_or_else(|| io::Error::other(format!("process {} is not in a cgroup v2", pid)))?;
+
+        Ok(Self { path, owned: false })
+    }
+
+    pub fn procs(&self) -> PathBuf {
+        self.path.join("cgroup.procs")
+    }
+
+    pub fn limit_memory(&self, bytes: u64) -> io::Result<()> {
+        fs::write(self.path.join("memory.max"), bytes.to_string())?;
+        let swap = self.path.join("memory.swap.max");
+        if swap.exists() {
+            fs::write(swap, "0")?;
+        }
+
+        Ok(())
+    }
+
+    /// Limits the number of processes and threads, lifting the limit if
+    /// there is none.
+    pub fn limit_pids(&self, count: Option<u64>) -> io::Result<()> {
+        let count = count.map_or_else(|| "max".to_string(), |count| count.to_string());
+        fs::write(self.path.join("pids.max"), count)
+    }
+
+    pub fn cpu_usage(&self) -> io::Result<Duration> {
+        Ok(Duration::from_micros(
+            self.stat("cpu.stat", "usage_usec")?.unwrap_or(0),
+        ))
+    }
+
+    pub fn memory_peak(&self) -> Option<u64> {
+        fs::read_to_string(self.path.join("memory.peak"))
+            .ok()
+            .and_then(|peak| peak.trim().parse().ok())
+    }
+
+    /// Resets the peak memory usage for reads through the returned file,
+    /// which kernels before 6.12 do not support.
+    pub fn reset_memory_peak(&self) -> io::Result<MemoryPeak> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.path.join("memory.peak"))?;
+        file.write_all(b"reset")?;
+        Ok(MemoryPeak(file))
+    }
+
+    pub fn oom_killed(&self) -> bool {
+        self.oom_kills() > 0
+    }
+
+    /// How many processes the OOM killer killed in the cgroup so far.
+    pub fn oom_kills(&self) -> u64 {
+        self.stat("memory.events", "oom_kill")
+            .ok()
+            .flatten()
+            .unwrap_or(0)
+    }
+
+    pub fn pids_exhausted(&self) -> bool {
+        self.pids_exhaustions() > 0
+    }
+
+    /// How many forks failed in the cgroup so far for hitting `pids.max`.
+    pub fn pids_exhaustions(&self) -> u64 {
+        self.stat("pids.events", "max").ok().flatten().unwrap_or(0)
+    }
+
+    /// Kills every process in the cgroup, falling back to signalling the
+    /// given process on kernels without `cgroup.kill`.
+    pub fn kill(&self, fallback: libc::pid_t) {
+        if fs::write(self.path.join("cgroup.kill"), "1").is_err() {
+            unsafe {
+                libc::kill(fallback, libc::SIGKILL);
+            }
+        }
+    }
+
+    /// Kills every process in the cgroup but `keep`, such as the init of a
+    /// container, until none of them is left to fork again.
+    pub fn kill_except(&self, keep: libc::pid_t) -> io::Result<()> {
+        for _ in 0..100 {
+            let pids = fs::read_to_string(self.procs())?
+                .lines()
+                .filter_map(|pid| pid.parse::<libc::pid_t>().ok())
+                .filter(|pid| *pid != keep)
+                .collect::<Vec<_>>();
+            if pids.is_empty() {
+                return Ok(());
+            }
+            for pid in pids {
+                unsafe {
+                    libc::kill(pid, libc::SIGKILL);
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        Err(io::Error::other(format!(
+            "processes of {} kept forking",
+            self.path.display()
+        )))
+    }
+
+    fn stat(&self, file: &str, key: &str) -> io::Result<Option<u64>> {
+        Ok(fs::read_to_string(self.path.join(file))?
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .find(|(name, _)| *name == key)
+            .and_then(|(_, value)| value.trim().parse().ok()))
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+        // the namespace init may still be tearing down its children
+        for _ in 0..100 {
+            if fs::remove_dir(&self.path).is_ok() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// Whether a path, or its closest existing ancestor, lies on a cgroup2
+/// filesystem.
+fn on_cgroup2(path: &Path) -> io::Result<bool> {
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or_else(|| Path::new("/"));
+    let existing = CString::new(existing.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat: libc::statfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statfs(existing.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // both types differ between targets
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_type as i64 == libc::CGROUP2_SUPER_MAGIC as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_other_filesystems_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let parent = dir.path().join("rayjudge");
+        assert!(!on_cgroup2(&parent).unwrap());
+        assert!(Cgroup::prepare(&parent).is_err());
+        assert!(!parent.exists());
+        assert!(!dir.path().join("cgroup.subtree_control").exists());
+    }
+}
@@ -0,0 +1,204 @@
+use super::executor::Execution;
+use crate::schema::Verdict;
+use serde::Deserialize;
+
+/// What a custom comparator or the interactor made of the output of a stage.
+pub struct Outcome {
+    pub status: Verdict,
+    pub score: i32,
+    pub message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Report {
+    status: Option<Verdict>,
+    score: Option<i32>,
+    message: Option<String>,
+}
+
+/// Interprets a comparator run as `<run> <input> <output> <answer>`, testlib
+/// style: exit code 0 means accepted, 1 wrong answer, 2 presentation error
+/// and 7 partially correct, with the score as the first word of stdout. The
+/// first line of stdout may instead be a JSON object like
+/// `{"status": "WA", "score": 5, "message": "..."}` whose fields take
+/// precedence. Any other exit code, such as testlib's 3 for a failed check,
+/// a crash or an exceeded limit is an error of the comparator, never of the
+/// contestant. The message defaults to what the comparator wrote to stderr.
+///
+/// The interactor is interpreted the same way, with its report file in place
+/// of stdout.
+pub fn interpret(
+    role: &str,
+    execution: &Execution,
+    stdout: &str,
+    stderr: Option<String>,
+    grade: i32,
+) -> Result<Outcome, String> {
+    let failure = |reason: String| {
+        Err(format!(
+            "the {} {}{}",
+            role,
+            reason,
+            stderr
+                .as_ref()
+                .map(|stderr| format!(": {}", stderr.trim_end()))
+                .unwrap_or_default()
+        ))
+    };
+    if execution.exceeded.is_some() {
+        return failure("exceeded its limits".to_string());
+    }
+    if let Some(signal) = execution.signal {
+        return failure(format!("was killed by signal {}", signal));
+    }
+
+    let first_line = stdout.lines().next().unwrap_or_default().trim();
+    let report = if first_line.starts_with('{') {
+        serde_json::from_str(first_line)
+            .map_err(|err| format!("the {} reported malformed JSON: {}", role, err))?
+    } else {
+        Report {
+            status: None,
+            score: None,
+            message: None,
+        }
+    };
+
+    let (status, score) = match (report.status, execution.exit_code) {
+        (Some(status), _) => (status, report.score),
+        (None, Some(0)) => (Verdict::Accepted, report.score),
+        (None, Some(1)) => (Verdict::WrongAnswer, report.score),
+        (None, Some(2)) => (Verdict::PresentationError, report.score),
+        (None, Some(7)) => {
+            let score = match report.score {
+                Some(score) => score,
+                None => match first_line.split_whitespace().next().map(str::parse) {
+                    Some(Ok(score)) => score,
+                    _ => return failure("reported partial credit without a score".to_string()),
+                },
+            };
+            let status = if score >= grade {
+                Verdict::Accepted
+            } else {
+                Verdict::WrongAnswer
+            };
+            (status, Some(score))
+        }
+        (None, code) => return failure(format!("failed with exit code {:?}", code)),
+    };
+
+    Ok(Outcome {
+        status,
+        score: score
+            .unwrap_or(if status == Verdict::Accepted {
+                grade
+            } else {
+                0
+            })
+            .clamp(0, grade.max(0)),
+        message: report.message.or(stderr),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::executor::Exceeded;
+
+    fn exited(code: i32) -> Execution {
+        Execution {
+            exit_code: Some(code),
+            ..Execution::default()
+        }
+    }
+
+    fn outcome(execution: &Execution, stdout: &str) -> Result<(Verdict, i32), String> {
+        interpret("comparator", execution, stdout, None, 100)
+            .map(|outcome| (outcome.status, outcome.score))
+    }
+
+    #[test]
+    fn maps_exit_codes() {
+        assert_eq!(outcome(&exited(0), ""), Ok((Verdict::Accepted, 100)));
+        assert_eq!(outcome(&exited(1), ""), Ok((Verdict::WrongAnswer, 0)));
+        assert_eq!(outcome(&exited(2), ""), Ok((Verdict::PresentationError, 0)));
+        assert_eq!(
+            outcome(&exited(3), ""),
+            Err("the comparator failed with exit code Some(3)".to_string())
+        );
+    }
+
+    #[test]
+    fn reads_partial_credit() {
+        assert_eq!(
+            outcome(&exited(7), "40 points\n"),
+            Ok((Verdict::WrongAnswer, 40))
+        );
+        assert_eq!(outcome(&exited(7), "100"), Ok((Verdict::Accepted, 100)));
+        assert_eq!(outcome(&exited(7), "150"), Ok((Verdict::Accepted, 100)));
+        assert_eq!(
+            outcome(&exited(7), "ok"),
+            Err("the comparator reported partial credit without a score".to_string())
+        );
+    }
+
+    #[test]
+    fn prefers_json_reports() {
+        let json = r#"{"status": "WA", "score": 5, "message": "close"}"#;
+        let report = interpret(
+            "interactor",
+            &exited(0),
+            json,
+            Some("ignored".to_string()),
+            100,
+        )
+        .unwrap();
+        assert_eq!(report.status, Verdict::WrongAnswer);
+        assert_eq!(report.score, 5);
+        assert_eq!(report.message.as_deref(), Some("close"));
+
+        assert_eq!(
+            outcome(&exited(7), r#"{"score": 60}"#),
+            Ok((Verdict::WrongAnswer, 60))
+        );
+        assert!(outcome(&exited(0), "{status}")
+            .unwrap_err()
+            .starts_with("the comparator reported malformed JSON: "));
+    }
+
+    #[test]
+    fn blames_the_comparator_for_crashes() {
+        let killed = Execution {
+            signal: Some(libc::SIGSEGV),
+            ..Execution::default()
+        };
+        assert_eq!(
+            interpret("comparator", &killed, "", Some("oops\n".to_string()), 100).err(),
+            Some(format!(
+                "the comparator was killed by signal {}: oops",
+                libc::SIGSEGV
+            ))
+        );
+        let exceeded = Execution {
+            exceeded: Some(Exceeded::Time),
+            ..exited(0)
+        };
+        assert_eq!(
+            outcome(&exceeded, "").err().as_deref(),
+            Some("the comparator exceeded its limits")
+        );
+    }
+
+    #[test]
+    fn defaults_the_message_to_stderr() {
+        let outcome = interpret(
+            "comparator",
+            &exited(1),
+            "",
+            Some("line 3 differs".to_string()),
+            100,
+        )
+        .unwrap();
+        assert_eq!(outcome.message.as_deref(), Some("line 3 differs"));
+    }
+}
@@ -0,0 +1,596 @@
+use super::{script, workspace};
+use crate::schema::{Stage, Verdict};
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+const SHOWN_LENGTH: usize = 32;
+/// How much of a line or token is held in memory, longer ones being compared
+/// by their length and hash beyond that
+const KEPT_LENGTH: usize = 4096;
+
+/// A built-in way of comparing the output of a stage to its answer, chosen
+/// by name in `Script.compare`. Every comparator streams both files, holding
+/// no more than `KEPT_LENGTH` bytes of a line or token.
+///
+/// `custom` names none of them, leaving the output to the custom comparator
+/// of the problem as if `Script.compare` was unset, and neither does a
+/// compare script.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Comparator {
+    /// Byte for byte
+    Exact,
+    /// Line by line, ignoring trailing whitespace and trailing empty lines,
+    /// a presentation error if only the whitespace differs
+    #[default]
+    Lines,
+    /// Whitespace separated tokens, also named `whitespace`
+    Tokens,
+    /// Tokens, numbers matching within the given absolute or relative error
+    Float(f64),
+    /// Lines in any order, ignoring trailing whitespace and empty lines
+    LinesUnordered,
+}
+
+pub struct Comparison {
+    pub status: Verdict,
+    /// Where the output first differs from the answer
+    pub mismatch: Option<String>,
+}
+
+impl Comparator {
+    /// The comparator a stage asks for, replicas falling back to the one of
+    /// their stage.
+    pub fn of_stage(stage: &Stage, parent: Option<&Stage>) -> Result<Option<Self>, String> {
+        script_compare(stage)
+            .or_else(|| parent.and_then(script_compare))
+            .filter(|name| *name != "custom" && !script::is_script(name))
+            .map(Comparator::parse)
+            .transpose()
+    }
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "exact" => Ok(Comparator::Exact),
+            "lines" => Ok(Comparator::Lines),
+            "tokens" | "whitespace" => Ok(Comparator::Tokens),
+            "lines-unordered" => Ok(Comparator::LinesUnordered),
+            name if name.starts_with("float:") => match name["float:".len()..].parse::<f64>() {
+                Ok(epsilon) if epsilon.is_finite() && epsilon >= 0.0 => {
+                    Ok(Comparator::Float(epsilon))
+                }
+                _ => Err(format!("invalid epsilon in comparator {}", name)),
+            },
+            name => Err(format!("unknown comparator {}", name)),
+        }
+    }
+
+    /// Compares an answer with an output, either of which may be below the
+    /// given workspace.
+    pub fn compare(
+        &self,
+        workspace: &Path,
+        answer: &Path,
+        output: &Path,
+    ) -> Result<Comparison, String> {
+        let open = |path: &Path| {
+            workspace::open(workspace, path)
+                .map(BufReader::new)
+                .map_err(|err| format!("failed to open {}: {}", path.display(), err))
+        };
+        let compare = |comparator: &Comparator| {
+            let (answer, output) = (open(answer)?, open(output)?);
+            match comparator {
+                Comparator::Exact => exact(answer, output),
+                Comparator::Lines => lines(answer, output),
+                Comparator::Tokens => tokens(answer, output, None),
+                Comparator::Float(epsilon) => tokens(answer, output, Some(*epsilon)),
+                Comparator::LinesUnordered => lines_unordered(answer, output),
+            }
+            .map_err(|err| format!("failed to compare output: {}", err))
+        };
+
+        let mismatch = match compare(self)? {
+            Some(mismatch) => mismatch,
+            None => {
+                return Ok(Comparison {
+                    status: Verdict::Accepted,
+                    mismatch: None,
+                })
+            }
+        };
+        // lines that only differ in whitespace are a presentation error
+        let status = if *self == Comparator::Lines && compare(&Comparator::Tokens)?.is_none() {
+            Verdict::PresentationError
+        } else {
+            Verdict::WrongAnswer
+        };
+
+        Ok(Comparison {
+            status,
+            mismatch: Some(mismatch),
+        })
+    }
+}
+
+fn script_compare(stage: &Stage) -> Option<&str> {
+    stage
+        .script
+        .as_ref()
+        .and_then(|script| script.compare.as_deref())
+}
+
+fn exact(answer: impl BufRead, output: impl BufRead) -> io::Result<Option<String>> {
+    let (mut answer, mut output) = (answer.bytes(), output.bytes());
+    let (mut line, mut column) = (1, 1);
+    loop {
+        match (answer.next().transpose()?, output.next().transpose()?) {
+            (None, None) => return Ok(None),
+            (Some(expected), Some(found)) if expected == found => {
+                if expected == b'\n' {
+                    line += 1;
+                    column = 1;
+                } else {
+                    column += 1;
+                }
+            }
+            (expected, found) => {
+                return Ok(Some(format!(
+                    "line {}, column {}: expected {}, found {}",
+                    line,
+                    column,
+                    describe_byte(expected),
+                    describe_byte(found)
+                )))
+            }
+        }
+    }
+}
+
+fn lines(answer: impl BufRead, output: impl BufRead) -> io::Result<Option<String>> {
+    let (mut answer, mut output) = (Reader::new(answer), Reader::new(output));
+    let mut line = 0;
+    loop {
+        line += 1;
+        match (answer.line()?, output.line()?) {
+            (None, None) => return Ok(None),
+            // whatever is left on the other side has to be empty lines
+            (None, Some(found)) if found.is_empty() => continue,
+            (Some(expected), None) if expected.is_empty() => continue,
+            (None, Some(found)) => {
+                return Ok(Some(format!(
+                    "line {}: expected end of output, found {}",
+                    line,
+                    found.show()
+                )))
+            }
+            (Some(expected), None) => {
+                return Ok(Some(format!(
+                    "line {}: expected {}, found end of output",
+                    line,
+                    expected.show()
+                )))
+            }
+            (Some(expected), Some(found)) if expected == found => (),
+            (Some(expected), Some(found)) => {
+                let column = expected
+                    .kept
+                    .iter()
+                    .zip(&found.kept)
+                    .take_while(|(expected, found)| expected == found)
+                    .count();
+                // the difference may lie beyond what is kept of both lines
+                let at = if column < KEPT_LENGTH {
+                    format!("line {}, column {}", line, column + 1)
+                } else {
+                    format!("line {}", line)
+                };
+                return Ok(Some(format!(
+                    "{}: expected {}, found {}",
+                    at,
+                    expected.show(),
+                    found.show()
+                )));
+            }
+        }
+    }
+}
+
+fn tokens(
+    answer: impl BufRead,
+    output: impl BufRead,
+    epsilon: Option<f64>,
+) -> io::Result<Option<String>> {
+    let (mut answer, mut output) = (Reader::new(answer), Reader::new(output));
+    let mut index = 0;
+    loop {
+        index += 1;
+        match (answer.token()?, output.token()?) {
+            (None, None) => return Ok(None),
+            (Some((expected, _)), Some((found, _)))
+                if token_matches(&expected, &found, epsilon) => {}
+            (expected, found) => {
+                return Ok(Some(format!(
+                    "token {} on line {}: expected {}, found {}",
+                    index,
+                    found.as_ref().map_or(output.line, |(_, line)| *line),
+                    expected.map_or("end of output".to_string(), |(token, _)| token.show()),
+                    found.map_or("end of output".to_string(), |(token, _)| token.show())
+                )));
+            }
+        }
+    }
+}
+
+fn token_matches(expected: &Chunk, found: &Chunk, epsilon: Option<f64>) -> bool {
+    if expected == found {
+        return true;
+    }
+
+    let number = |token: &Chunk| -> Option<f64> {
+        if token.len > KEPT_LENGTH {
+            return None;
+        }
+        std::str::from_utf8(&token.kept)
+            .ok()?
+            .parse()
+            .ok()
+            .filter(|number: &f64| number.is_finite())
+    };
+    match (epsilon, number(expected), number(found)) {
+        (Some(epsilon), Some(expected), Some(found)) => {
+            let error = (expected - found).abs();
+            error <= epsilon || error <= epsilon * expected.abs()
+        }
+        _ => false,
+    }
+}
+
+/// Counts the lines of the answer by hash so that neither file has to be
+/// held in memory.
+fn lines_unordered(answer: impl BufRead, output: impl BufRead) -> io::Result<Option<String>> {
+    let (mut answer, mut output) = (Reader::new(answer), Reader::new(output));
+    let mut expected = HashMap::new();
+    while let Some(line) = answer.line()? {
+        if !line.is_empty() {
+            *expected.entry((line.len, line.hash)).or_insert(0usize) += 1;
+        }
+    }
+
+    let mut number = 0;
+    while let Some(line) = output.line()? {
+        number += 1;
+        if line.is_empty() {
+            continue;
+        }
+        match expected.get_mut(&(line.len, line.hash)) {
+            Some(count) if *count > 0 => *count -= 1,
+            _ => {
+                return Ok(Some(format!(
+                    "line {}: {} is not expected",
+                    number,
+                    line.show()
+                )))
+            }
+        }
+    }
+
+    let missing: usize = expected.values().sum();
+    if missing > 0 {
+        Ok(Some(format!("{} expected lines are missing", missing)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// A line or token of which only the first `KEPT_LENGTH` bytes are held in
+/// memory, the rest only counting towards its length and hash.
+#[derive(PartialEq, Debug)]
+struct Chunk {
+    kept: Vec<u8>,
+    len: usize,
+    /// FNV-1a, which can be computed a byte at a time
+    hash: u64,
+}
+
+impl Chunk {
+    fn new() -> Self {
+        Self {
+            kept: Vec::new(),
+            len: 0,
+            hash: 0xcbf2_9ce4_8422_2325,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.kept.len() < KEPT_LENGTH {
+            self.kept.push(byte);
+        }
+        self.len += 1;
+        self.hash = (self.hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn show(&self) -> String {
+        let shown = String::from_utf8_lossy(&self.kept[..self.kept.len().min(SHOWN_LENGTH)]);
+        if self.len > SHOWN_LENGTH {
+            format!("{:?}...", shown)
+        } else {
+            format!("{:?}", shown)
+        }
+    }
+}
+
+struct Reader<R: BufRead> {
+    reader: R,
+    /// The line the next token is looked for on
+    line: usize,
+}
+
+impl<R: BufRead> Reader<R> {
+    fn new(reader: R) -> Self {
+        Self { reader, line: 1 }
+    }
+
+    /// Reads the next line without its trailing whitespace.
+    fn line(&mut self) -> io::Result<Option<Chunk>> {
+        let mut line: Option<Chunk> = None;
+        // the length and hash of the line up to its last non-whitespace
+        let mut trimmed = (0, Chunk::new().hash);
+        loop {
+            let buffer = self.reader.fill_buf()?;
+            if buffer.is_empty() {
+                break;
+            }
+            let chunk = line.get_or_insert_with(Chunk::new);
+            let end = buffer.iter().position(|byte| *byte == b'\n');
+            for byte in &buffer[..end.unwrap_or(buffer.len())] {
+                chunk.push(*byte);
+                if !byte.is_ascii_whitespace() {
+                    trimmed = (chunk.len, chunk.hash);
+                }
+            }
+            let consumed = end.map_or(buffer.len(), |end| end + 1);
+            self.reader.consume(consumed);
+            if end.is_some() {
+                break;
+            }
+        }
+
+        Ok(line.map(|mut line| {
+            line.len = trimmed.0;
+            line.hash = trimmed.1;
+            line.kept.truncate(line.len);
+            line
+        }))
+    }
+
+    /// Reads the next token along with the line it is on.
+    fn token(&mut self) -> io::Result<Option<(Chunk, usize)>> {
+        let mut token: Option<(Chunk, usize)> = None;
+        loop {
+            let buffer = self.reader.fill_buf()?;
+            if buffer.is_empty() {
+                return Ok(token);
+            }
+            let mut consumed = 0;
+            for byte in buffer {
+                if byte.is_ascii_whitespace() {
+                    if token.is_some() {
+                        break;
+                    }
+                    if *byte == b'\n' {
+                        self.line += 1;
+                    }
+                } else {
+                    let line = self.line;
+                    token
+                        .get_or_insert_with(|| (Chunk::new(), line))
+                        .0
+                        .push(*byte);
+                }
+                consumed += 1;
+            }
+            let ended = consumed < buffer.len();
+            self.reader.consume(consumed);
+            if ended {
+                return Ok(token);
+            }
+        }
+    }
+}
+
+fn describe_byte(byte: Option<u8>) -> String {
+    match byte {
+        Some(byte) => format!("{:?}", byte as char),
+        None => "end of output".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn compare(comparator: Comparator, answer: &str, output: &str) -> Comparison {
+        let dir = tempfile::tempdir().unwrap();
+        let (answer_path, output_path) = (dir.path().join("answer"), dir.path().join("output"));
+        fs::write(&answer_path, answer).unwrap();
+        fs::write(&output_path, output).unwrap();
+        comparator
+            .compare(dir.path(), &answer_path, &output_path)
+            .unwrap()
+    }
+
+    fn status(comparator: Comparator, answer: &str, output: &str) -> Verdict {
+        compare(comparator, answer, output).status
+    }
+
+    #[test]
+    fn parses_names() {
+        assert_eq!(Comparator::parse("exact"), Ok(Comparator::Exact));
+        assert_eq!(Comparator::parse("lines"), Ok(Comparator::Lines));
+        assert_eq!(Comparator::parse("tokens"), Ok(Comparator::Tokens));
+        assert_eq!(Comparator::parse("whitespace"), Ok(Comparator::Tokens));
+        assert_eq!(
+            Comparator::parse("lines-unordered"),
+            Ok(Comparator::LinesUnordered)
+        );
+        assert_eq!(Comparator::parse("float:1e-6"), Ok(Comparator::Float(1e-6)));
+        assert_eq!(Comparator::parse("float:0"), Ok(Comparator::Float(0.0)));
+        assert!(Comparator::parse("bytes").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_tolerances() {
+        for name in ["float:", "float:x", "float:-0.1", "float:inf", "float:NaN"].iter() {
+            assert_eq!(
+                Comparator::parse(name),
+                Err(format!("invalid epsilon in comparator {}", name))
+            );
+        }
+    }
+
+    #[test]
+    fn compares_exactly() {
+        assert_eq!(
+            status(Comparator::Exact, "1 2\n", "1 2\n"),
+            Verdict::Accepted
+        );
+        let comparison = compare(Comparator::Exact, "1 2\n", "1 2");
+        assert_eq!(comparison.status, Verdict::WrongAnswer);
+        assert_eq!(
+            comparison.mismatch.as_deref(),
+            Some("line 1, column 4: expected '\\n', found end of output")
+        );
+    }
+
+    #[test]
+    fn ignores_trailing_whitespace_of_lines() {
+        let answer = "1 2\n3\n";
+        assert_eq!(status(Comparator::Lines, answer, answer), Verdict::Accepted);
+        assert_eq!(
+            status(Comparator::Lines, answer, "1 2  \r\n3\n\n\n"),
+            Verdict::Accepted
+        );
+        assert_eq!(status(Comparator::Lines, "1\n\n", "1"), Verdict::Accepted);
+    }
+
+    #[test]
+    fn tells_presentation_errors_from_wrong_answers() {
+        let answer = "1 2\n3\n";
+        let comparison = compare(Comparator::Lines, answer, "1  2\n3\n");
+        assert_eq!(comparison.status, Verdict::PresentationError);
+        assert_eq!(
+            comparison.mismatch.as_deref(),
+            Some("line 1, column 3: expected \"1 2\", found \"1  2\"")
+        );
+        assert_eq!(
+            status(Comparator::Lines, answer, "1 2 3\n"),
+            Verdict::PresentationError
+        );
+
+        let comparison = compare(Comparator::Lines, answer, "1 2\n4\n");
+        assert_eq!(comparison.status, Verdict::WrongAnswer);
+        assert_eq!(
+            comparison.mismatch.as_deref(),
+            Some("line 2, column 1: expected \"3\", found \"4\"")
+        );
+        assert_eq!(
+            compare(Comparator::Lines, answer, "1 2\n")
+                .mismatch
+                .as_deref(),
+            Some("line 2: expected \"3\", found end of output")
+        );
+    }
+
+    #[test]
+    fn compares_tokens() {
+        for comparator in [Comparator::Tokens, Comparator::parse("whitespace").unwrap()].iter() {
+            assert_eq!(
+                status(*comparator, "1 2\n3\n", " 1\n2\t3"),
+                Verdict::Accepted
+            );
+            let comparison = compare(*comparator, "1 2\n3\n", "1\n2\n4");
+            assert_eq!(comparison.status, Verdict::WrongAnswer);
+            assert_eq!(
+                comparison.mismatch.as_deref(),
+                Some("token 3 on line 3: expected \"3\", found \"4\"")
+            );
+            assert_eq!(
+                compare(*comparator, "1 2", "1 2 3").mismatch.as_deref(),
+                Some("token 3 on line 1: expected end of output, found \"3\"")
+            );
+        }
+    }
+
+    #[test]
+    fn compares_floats_within_tolerance() {
+        let comparator = Comparator::Float(1e-3);
+        assert_eq!(status(comparator, "1.0 x\n", "1.0005 x"), Verdict::Accepted);
+        assert_eq!(status(comparator, "1.0\n", "1.002\n"), Verdict::WrongAnswer);
+        // the error may be relative to large answers
+        assert_eq!(status(comparator, "10000\n", "10005\n"), Verdict::Accepted);
+        assert_eq!(status(comparator, "1.0\n", "nan\n"), Verdict::WrongAnswer);
+        assert_eq!(
+            status(Comparator::Tokens, "1.0\n", "1.0005\n"),
+            Verdict::WrongAnswer
+        );
+    }
+
+    #[test]
+    fn compares_lines_in_any_order() {
+        let comparator = Comparator::LinesUnordered;
+        assert_eq!(
+            status(comparator, "a\nb\nb\n", "b\na \n\nb"),
+            Verdict::Accepted
+        );
+        assert_eq!(
+            compare(comparator, "a\nb\nb\n", "b\na\na\n")
+                .mismatch
+                .as_deref(),
+            Some("line 3: \"a\" is not expected")
+        );
+        assert_eq!(
+            compare(comparator, "a\nb\n", "b\n").mismatch.as_deref(),
+            Some("1 expected lines are missing")
+        );
+    }
+
+    #[test]
+    fn compares_lines_longer_than_kept() {
+        let long = "7".repeat(KEPT_LENGTH * 3);
+        let answer = format!("{}\n", long);
+        assert_eq!(
+            status(Comparator::Lines, &answer, &format!("{} \n", long)),
+            Verdict::Accepted
+        );
+        assert_eq!(
+            status(Comparator::Tokens, &answer, &long),
+            Verdict::Accepted
+        );
+
+        let output = format!("{}8\n", &long[1..]);
+        let comparison = compare(Comparator::Lines, &answer, &output);
+        assert_eq!(comparison.status, Verdict::WrongAnswer);
+        // both lines only differ beyond what is kept of them
+        let shown = format!("{:?}...", "7".repeat(SHOWN_LENGTH));
+        assert_eq!(
+            comparison.mismatch,
+            Some(format!("line 1: expected {}, found {}", shown, shown))
+        );
+        assert_eq!(
+            status(Comparator::Tokens, &answer, &output),
+            Verdict::WrongAnswer
+        );
+        assert_eq!(
+            status(Comparator::LinesUnordered, &answer, &output),
+            Verdict::WrongAnswer
+        );
+    }
+}
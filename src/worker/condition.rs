@@ -0,0 +1,426 @@
+use crate::schema::StageResult;
+use std::{iter::Peekable, str::Chars};
+
+const VARIABLES: &[&str] = &[
+    "status",
+    "score",
+    "time",
+    "wall_time",
+    "memory",
+    "exit_code",
+    "signal",
+];
+
+#[derive(Clone, PartialEq, Debug)]
+enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Operator {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Literal(Value),
+    Variable(String),
+    Not(Box<Expr>),
+    Binary(Box<Expr>, Operator, Box<Expr>),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Ident(String),
+    Int(i64),
+    Str(String),
+    Operator(Operator),
+    Not,
+    Open,
+    Close,
+}
+
+/// A `Require.cond` expression such as `status == "AC" && score >= 50`,
+/// evaluated against the result of the stage it depends on.
+#[derive(Debug)]
+pub struct Condition {
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+        };
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.position) {
+            return Err(format!("unexpected {:?} in condition {}", token, source));
+        }
+
+        Ok(Self { expr })
+    }
+
+    pub fn eval(&self, result: &StageResult) -> Result<bool, String> {
+        match eval(&self.expr, result)? {
+            Value::Bool(value) => Ok(value),
+            value => Err(format!(
+                "condition evaluated to {:?} instead of a bool",
+                value
+            )),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        let token = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '(' => {
+                chars.next();
+                Token::Open
+            }
+            ')' => {
+                chars.next();
+                Token::Close
+            }
+            '"' | '\'' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => break,
+                        Some(next) => value.push(next),
+                        None => return Err(format!("unterminated string in {}", source)),
+                    }
+                }
+                Token::Str(value)
+            }
+            c if c.is_ascii_digit() => {
+                let digits = take_while(&mut chars, |c| c.is_ascii_digit());
+                Token::Int(
+                    digits
+                        .parse()
+                        .map_err(|_| format!("number {} is too large", digits))?,
+                )
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                Token::Ident(take_while(&mut chars, |c| c.is_alphanumeric() || c == '_'))
+            }
+            _ => {
+                let symbol = take_while(&mut chars, |c| "=!<>&|".contains(c));
+                match symbol.as_str() {
+                    "||" => Token::Operator(Operator::Or),
+                    "&&" => Token::Operator(Operator::And),
+                    "==" => Token::Operator(Operator::Eq),
+                    "!=" => Token::Operator(Operator::Ne),
+                    "<" => Token::Operator(Operator::Lt),
+                    "<=" => Token::Operator(Operator::Le),
+                    ">" => Token::Operator(Operator::Gt),
+                    ">=" => Token::Operator(Operator::Ge),
+                    "!" => Token::Not,
+                    "" => return Err(format!("unexpected {} in condition {}", c, source)),
+                    symbol => {
+                        return Err(format!(
+                            "unknown operator {} in condition {}",
+                            symbol, source
+                        ))
+                    }
+                }
+            }
+        };
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+fn take_while(chars: &mut Peekable<Chars>, predicate: impl Fn(char) -> bool) -> String {
+    let mut value = String::new();
+    while let Some(&c) = chars.peek() {
+        if !predicate(c) {
+            break;
+        }
+        value.push(c);
+        chars.next();
+    }
+
+    value
+}
+
+/// A recursive descent parser, `||` binding loosest and comparisons tightest.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn accept(&mut self, operators: &[Operator]) -> Option<Operator> {
+        match self.tokens.get(self.position) {
+            Some(Token::Operator(operator)) if operators.contains(operator) => {
+                self.position += 1;
+                Some(*operator)
+            }
+            _ => None,
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while let Some(operator) = self.accept(&[Operator::Or]) {
+            expr = Expr::Binary(Box::new(expr), operator, Box::new(self.and()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.comparison()?;
+        while let Some(operator) = self.accept(&[Operator::And]) {
+            expr = Expr::Binary(Box::new(expr), operator, Box::new(self.comparison()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let expr = self.unary()?;
+        let operators = [
+            Operator::Eq,
+            Operator::Ne,
+            Operator::Lt,
+            Operator::Le,
+            Operator::Gt,
+            Operator::Ge,
+        ];
+        match self.accept(&operators) {
+            Some(operator) => Ok(Expr::Binary(
+                Box::new(expr),
+                operator,
+                Box::new(self.unary()?),
+            )),
+            None => Ok(expr),
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err("missing ) in condition".to_string()),
+                }
+            }
+            Some(Token::Int(value)) => Ok(Expr::Literal(Value::Int(value))),
+            Some(Token::Str(value)) => Ok(Expr::Literal(Value::Str(value))),
+            Some(Token::Ident(name)) => match name.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                name if VARIABLES.contains(&name) => Ok(Expr::Variable(name.to_string())),
+                name => Err(format!("unknown variable {} in condition", name)),
+            },
+            Some(token) => Err(format!("unexpected {:?} in condition", token)),
+            None => Err("unexpected end of condition".to_string()),
+        }
+    }
+}
+
+fn variable(name: &str, result: &StageResult) -> Value {
+    let int = |value: Option<i64>| value.map_or(Value::Null, Value::Int);
+
+    match name {
+        "status" => Value::Str(result.status.to_string()),
+        "score" => Value::Int(result.score as i64),
+        "time" => Value::Int(result.time as i64),
+        "wall_time" => Value::Int(result.wall_time as i64),
+        "memory" => Value::Int(result.memory as i64),
+        "exit_code" => int(result.exit_code.map(i64::from)),
+        "signal" => int(result.signal.map(i64::from)),
+        _ => unreachable!(),
+    }
+}
+
+fn eval(expr: &Expr, result: &StageResult) -> Result<Value, String> {
+    match expr {
+        Expr::Literal(value) => Ok(value.clone()),
+        Expr::Variable(name) => Ok(variable(name, result)),
+        Expr::Not(expr) => match eval(expr, result)? {
+            Value::Bool(value) => Ok(Value::Bool(!value)),
+            value => Err(format!("cannot negate {:?}", value)),
+        },
+        Expr::Binary(left, operator, right) => {
+            let left = eval(left, result)?;
+            // short circuit so that e.g. `exit_code != null && exit_code > 0`
+            // never compares null
+            match (operator, &left) {
+                (Operator::Or, Value::Bool(true)) => return Ok(Value::Bool(true)),
+                (Operator::And, Value::Bool(false)) => return Ok(Value::Bool(false)),
+                _ => (),
+            }
+            let right = eval(right, result)?;
+
+            let value = match (operator, &left, &right) {
+                (Operator::Or, Value::Bool(_), Value::Bool(right))
+                | (Operator::And, Value::Bool(_), Value::Bool(right)) => *right,
+                (Operator::Eq, _, _) => left == right,
+                (Operator::Ne, _, _) => left != right,
+                (Operator::Lt, Value::Int(left), Value::Int(right)) => left < right,
+                (Operator::Le, Value::Int(left), Value::Int(right)) => left <= right,
+                (Operator::Gt, Value::Int(left), Value::Int(right)) => left > right,
+                (Operator::Ge, Value::Int(left), Value::Int(right)) => left >= right,
+                _ => {
+                    return Err(format!(
+                        "cannot apply {:?} to {:?} and {:?}",
+                        operator, left, right
+                    ))
+                }
+            };
+
+            Ok(Value::Bool(value))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::{Stage, Verdict};
+
+    fn result(status: Verdict, score: i32, exit_code: Option<i32>) -> StageResult {
+        let stage: Stage = serde_json::from_str(r#"{"name": "test", "grade": 100}"#).unwrap();
+        StageResult {
+            status,
+            score,
+            exit_code,
+            ..StageResult::accepted(&stage)
+        }
+    }
+
+    fn eval(source: &str, result: &StageResult) -> Result<bool, String> {
+        Condition::parse(source)?.eval(result)
+    }
+
+    #[test]
+    fn binds_and_tighter_than_or() {
+        let accepted = result(Verdict::Accepted, 100, Some(0));
+        assert_eq!(eval("true || false && false", &accepted), Ok(true));
+        assert_eq!(eval("(true || false) && false", &accepted), Ok(false));
+        assert_eq!(eval("false && true || true", &accepted), Ok(true));
+        assert_eq!(
+            eval(
+                r#"status == "WA" || status == "AC" && score >= 50"#,
+                &accepted
+            ),
+            Ok(true)
+        );
+        assert_eq!(
+            eval(
+                r#"(status == "WA" || status == "AC") && score < 50"#,
+                &accepted
+            ),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn binds_not_tightest() {
+        let accepted = result(Verdict::Accepted, 100, Some(0));
+        assert_eq!(eval(r#"!(status == "AC")"#, &accepted), Ok(false));
+        assert_eq!(eval("!false && !(score < 50)", &accepted), Ok(true));
+        assert_eq!(
+            eval(r#"!status == "AC""#, &accepted),
+            Err(r#"cannot negate Str("AC")"#.to_string())
+        );
+    }
+
+    #[test]
+    fn compares_variables() {
+        let wrong = result(Verdict::WrongAnswer, 30, Some(1));
+        assert_eq!(eval(r#"status != 'AC' && score > 20"#, &wrong), Ok(true));
+        assert_eq!(eval("score <= 29 || exit_code == 0", &wrong), Ok(false));
+        assert_eq!(eval("signal == null", &wrong), Ok(true));
+    }
+
+    #[test]
+    fn short_circuits() {
+        let crashed = result(Verdict::RuntimeError, 0, None);
+        assert_eq!(
+            eval("exit_code != null && exit_code > 0", &crashed),
+            Ok(false)
+        );
+        assert_eq!(
+            eval("exit_code == null || exit_code > 0", &crashed),
+            Ok(true)
+        );
+        assert_eq!(
+            eval("exit_code > 0", &crashed),
+            Err("cannot apply Gt to Null and Int(0)".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_conditions() {
+        let errors = [
+            ("status == ", "unexpected end of condition"),
+            (r#"(status == "AC""#, "missing ) in condition"),
+            (
+                r#"status == "AC"#,
+                r#"unterminated string in status == "AC"#,
+            ),
+            (
+                "1 < 2 < 3",
+                "unexpected Operator(Lt) in condition 1 < 2 < 3",
+            ),
+            ("status = 1", "unknown operator = in condition status = 1"),
+            ("score & 1", "unknown operator & in condition score & 1"),
+            ("score # 1", "unexpected # in condition score # 1"),
+            ("verdict == 1", "unknown variable verdict in condition"),
+            (
+                "score == 99999999999999999999",
+                "number 99999999999999999999 is too large",
+            ),
+            ("score == )", "unexpected Close in condition"),
+        ];
+        for (source, error) in errors.iter() {
+            assert_eq!(Condition::parse(source).unwrap_err(), *error, "{}", source);
+        }
+    }
+
+    #[test]
+    fn requires_a_bool() {
+        let accepted = result(Verdict::Accepted, 100, Some(0));
+        assert_eq!(
+            eval("score", &accepted),
+            Err("condition evaluated to Int(100) instead of a bool".to_string())
+        );
+        assert_eq!(
+            eval("score && true", &accepted),
+            Err("cannot apply And to Int(100) and Bool(true)".to_string())
+        );
+    }
+}
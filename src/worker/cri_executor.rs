@@ -0,0 +1,366 @@
+use super::{
+    cgroup::{Cgroup, MemoryPeak},
+    executor::{is_pipe, Environment, Exceeded, Execution, Executor, Limits},
+    workspace,
+};
+use crate::{
+    cri::{
+        ContainerConfig, ContainerMetadata, ImageSpec, Int64Value, LinuxContainerConfig,
+        LinuxContainerResources, LinuxContainerSecurityContext, Mount, PodSandboxConfig,
+        PodSandboxMetadata,
+    },
+    cri_runtime::CriRuntime,
+};
+use async_std::{future::timeout, sync::Mutex};
+use async_trait::async_trait;
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    os::unix::fs::chown,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+static POD_SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+const SANDBOX_DIR: &str = "/sandbox";
+const NAMESPACE: &str = "rayjudge";
+const NOBODY: u32 = 65534;
+const CPU_PERIOD: i64 = 100_000;
+
+/// Runs programs in containers of a CRI runtime such as containerd or
+/// CRI-O, one pod per submission.
+pub struct CriExecutor {
+    runtime: CriRuntime,
+    work_dir: PathBuf,
+    image: String,
+    pulled: Mutex<HashSet<String>>,
+}
+
+impl CriExecutor {
+    pub fn new(runtime: CriRuntime, work_dir: PathBuf, image: String) -> Self {
+        Self {
+            runtime,
+            work_dir,
+            image,
+            pulled: Mutex::new(HashSet::new()),
+        }
+    }
+
+    async fn pull(&self, image: &str) -> Result<(), String> {
+        let mut pulled = self.pulled.lock().await;
+        if !pulled.contains(image) {
+            self.runtime.pull_image(image).await?;
+            pulled.insert(image.to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Executor for CriExecutor {
+    async fn prepare(
+        &self,
+        name: &str,
+        image: Option<&str>,
+    ) -> Result<Box<dyn Environment>, String> {
+        let image = image.unwrap_or(&self.image);
+        let name = format!(
+            "{}-{}-{}",
+            name,
+            std::process::id(),
+            POD_SEQUENCE.fetch_add(1, Ordering::SeqCst)
+        );
+        let workspace = self.work_dir.join(&name);
+        fs::create_dir_all(&workspace)
+            .and_then(|_| own(&workspace))
+            .map_err(|err| format!("failed to prepare workspace: {}", err))?;
+
+        self.pull(image).await?;
+
+        let mut metadata = PodSandboxMetadata::new();
+        metadata.set_name(format!("rayjudge-{}", name));
+        metadata.set_uid(name.clone());
+        metadata.set_namespace(NAMESPACE.to_string());
+        let mut sandbox_config = PodSandboxConfig::new();
+        sandbox_config.set_metadata(metadata);
+        sandbox_config.set_hostname(NAMESPACE.to_string());
+
+        let pod = self.runtime.run_pod_sandbox(sandbox_config.clone()).await?;
+        let mut env = CriEnvironment {
+            runtime: self.runtime.clone(),
+            workspace,
+            pod,
+            container: None,
+            lock: Mutex::new(()),
+        };
+
+        let container = self.create_container(&env, image, sandbox_config).await;
+        match container {
+            Ok(container) => env.container = Some(container),
+            Err(err) => {
+                Box::new(env).cleanup().await;
+                return Err(err);
+            }
+        }
+
+        Ok(Box::new(env))
+    }
+}
+
+impl CriExecutor {
+    async fn create_container(
+        &self,
+        env: &CriEnvironment,
+        image: &str,
+        sandbox_config: PodSandboxConfig,
+    ) -> Result<Container, String> {
+        let mut metadata = ContainerMetadata::new();
+        metadata.set_name("judge".to_string());
+        let mut image_spec = ImageSpec::new();
+        image_spec.set_image(image.to_string());
+        let mut mount = Mount::new();
+        mount.set_container_path(SANDBOX_DIR.to_string());
+        mount.set_host_path(env.workspace.to_string_lossy().into_owned());
+
+        let mut user = Int64Value::new();
+        user.set_value(NOBODY as i64);
+        let mut security_context = LinuxContainerSecurityContext::new();
+        security_context.set_run_as_user(user);
+        security_context.set_no_new_privs(true);
+        let mut linux = LinuxContainerConfig::new();
+        linux.set_security_context(security_context);
+
+        let mut config = ContainerConfig::new();
+        config.set_metadata(metadata);
+        config.set_image(image_spec);
+        config.set_command(vec!["sleep".to_string(), "infinity".to_string()].into());
+        config.set_working_dir(SANDBOX_DIR.to_string());
+        config.set_mounts(vec![mount].into());
+        config.set_linux(linux);
+
+        let id = self
+            .runtime
+            .create_container(&env.pod, config, sandbox_config)
+            .await?;
+        self.runtime.start_container(&id).await?;
+
+        // the CRI cannot limit processes nor tell the peak memory usage and
+        // why a program was killed, the cgroup of the container on the host
+        // does
+        let init = init_pid(&self.runtime.container_info(&id).await?)?;
+        let cgroup = Cgroup::of_process(init)
+            .map_err(|err| format!("failed to find the cgroup of container {}: {}", id, err))?;
+
+        Ok(Container { id, init, cgroup })
+    }
+}
+
+struct CriEnvironment {
+    runtime: CriRuntime,
+    workspace: PathBuf,
+    pod: String,
+    container: Option<Container>,
+    lock: Mutex<()>,
+}
+
+struct Container {
+    id: String,
+    init: libc::pid_t,
+    cgroup: Cgroup,
+}
+
+impl CriEnvironment {
+    /// Maps a host path inside the workspace to where the container sees it.
+    fn container_path(&self, path: &Path) -> Result<String, String> {
+        path.strip_prefix(&self.workspace)
+            .map(|relative| {
+                Path::new(SANDBOX_DIR)
+                    .join(relative)
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .map_err(|_| format!("{} is outside of the workspace", path.display()))
+    }
+
+    async fn cpu_usage(&self, container: &str) -> Result<Duration, String> {
+        let stats = self.runtime.container_stats(container).await?;
+        Ok(Duration::from_nanos(
+            stats.get_cpu().get_usage_core_nano_seconds().value,
+        ))
+    }
+}
+
+#[async_trait]
+impl Environment for CriEnvironment {
+    fn workspace(&self) -> &Path {
+        &self.workspace
+    }
+
+    async fn run(
+        &self,
+        args: &[String],
+        limits: &Limits,
+        stdin: Option<&Path>,
+        stdout: &Path,
+        stderr: &Path,
+    ) -> Result<Execution, String> {
+        let container = self.container.as_ref().unwrap();
+        // runs share the accounting and limits of the container, so they
+        // take turns
+        let _guard = self.lock.lock().await;
+
+        let stdin = match stdin {
+            Some(path) if path.starts_with(&self.workspace) => self.container_path(path)?,
+            Some(path) => {
+                let copy = self.workspace.join(".stdin");
+                workspace::open(&self.workspace, path)
+                    .and_then(|mut source| {
+                        io::copy(&mut source, &mut workspace::create(&self.workspace, &copy)?)
+                    })
+                    .map_err(|err| format!("failed to copy {}: {}", path.display(), err))?;
+                self.container_path(&copy)?
+            }
+            None => "/dev/null".to_string(),
+        };
+
+        let mut resources = LinuxContainerResources::new();
+        resources.set_cpu_period(CPU_PERIOD);
+        resources.set_cpu_quota(CPU_PERIOD);
+        if let Some(memory) = limits.memory {
+            resources.set_memory_limit_in_bytes(memory as i64);
+        }
+        self.runtime
+            .update_container_resources(&container.id, resources)
+            .await?;
+        // the init of the container counts as well
+        container
+            .cgroup
+            .limit_pids(limits.proc.map(|proc| proc + 1))
+            .map_err(|err| format!("failed to limit processes: {}", err))?;
+        let (oom_kills, pids_exhaustions) = (
+            container.cgroup.oom_kills(),
+            container.cgroup.pids_exhaustions(),
+        );
+        // older kernels only tell the peak of the container as a whole
+        let mut peak = container.cgroup.reset_memory_peak().ok();
+
+        // the shell applies the file size limit in 512 byte blocks and the
+        // redirections, then gets replaced by the judged program
+        let mut script = String::new();
+        if let Some(file) = limits.file {
+            script.push_str(&format!("ulimit -f {} && ", file.div_ceil(512)));
+        }
+        script.push_str(&format!(
+            "exec \"$@\" {} {} < {} 2> {}",
+            if is_pipe(stdout) { "1<>" } else { ">" },
+            quote(&self.container_path(stdout)?),
+            quote(&stdin),
+            quote(&self.container_path(stderr)?)
+        ));
+        let mut cmd = vec!["sh".to_string(), "-c".to_string(), script, "sh".to_string()];
+        cmd.extend(args.iter().cloned());
+
+        let wall_limit = limits.wall_time();
+        let before = self.cpu_usage(&container.id).await?;
+        let start = Instant::now();
+        let exec = self.runtime.exec_sync(
+            &container.id,
+            cmd,
+            wall_limit.map_or(0, |wall| wall.as_secs() as i64 + 1),
+        );
+        let response = match wall_limit {
+            Some(wall) => timeout(wall, exec).await.ok(),
+            None => Some(exec.await),
+        };
+        let wall_time = start.elapsed();
+        let exit_code = match response {
+            Some(Ok(response)) => Some(response.exit_code),
+            Some(Err(_)) | None if wall_limit.is_some_and(|wall| wall_time >= wall) => None,
+            Some(Err(err)) => return Err(err),
+            None => None,
+        };
+        if exit_code.is_none() {
+            // the runtime leaves the program running once the call is dropped
+            container
+                .cgroup
+                .kill_except(container.init)
+                .map_err(|err| format!("failed to kill timed out program: {}", err))?;
+        }
+        let cpu_time = self.cpu_usage(&container.id).await?.saturating_sub(before);
+        let memory = peak
+            .as_mut()
+            .and_then(MemoryPeak::read)
+            .or_else(|| container.cgroup.memory_peak())
+            .unwrap_or(0);
+        // the runtime reports programs killed by a signal as 128 + signal
+        let signal = exit_code.filter(|code| *code > 128).map(|code| code - 128);
+
+        let exceeded = if exit_code.is_none() {
+            Some(Exceeded::Time)
+        } else if container.cgroup.oom_kills() > oom_kills {
+            Some(Exceeded::Memory)
+        } else if signal == Some(libc::SIGXFSZ) {
+            Some(Exceeded::Output)
+        } else if signal == Some(libc::SIGXCPU) || limits.time.is_some_and(|time| cpu_time > time) {
+            Some(Exceeded::Time)
+        } else if container.cgroup.pids_exhaustions() > pids_exhaustions {
+            Some(Exceeded::Process)
+        } else {
+            None
+        };
+
+        Ok(Execution {
+            exit_code: exit_code.filter(|_| signal.is_none()),
+            signal,
+            cpu_time,
+            wall_time,
+            memory,
+            exceeded,
+        })
+    }
+
+    async fn cleanup(self: Box<Self>) {
+        if let Some(container) = &self.container {
+            let container = &container.id;
+            if let Err(err) = self.runtime.stop_container(container, 0).await {
+                warn!(container = %container, "failed to stop container: {}", err);
+            }
+            if let Err(err) = self.runtime.remove_container(container).await {
+                warn!(container = %container, "failed to remove container: {}", err);
+            }
+        }
+        if let Err(err) = self.runtime.stop_pod_sandbox(&self.pod).await {
+            warn!(pod = %self.pod, "failed to stop pod: {}", err);
+        }
+        if let Err(err) = self.runtime.remove_pod_sandbox(&self.pod).await {
+            warn!(pod = %self.pod, "failed to remove pod: {}", err);
+        }
+        let _ = fs::remove_dir_all(&self.workspace);
+    }
+}
+
+fn own(path: &Path) -> io::Result<()> {
+    if unsafe { libc::geteuid() } == 0 {
+        chown(path, Some(NOBODY), Some(NOBODY))
+    } else {
+        Ok(())
+    }
+}
+
+/// The pid of the init process of a container, which containerd and CRI-O
+/// tell in the `info` of its verbose status.
+fn init_pid(info: &HashMap<String, String>) -> Result<libc::pid_t, String> {
+    info.get("info")
+        .and_then(|info| serde_json::from_str::<serde_json::Value>(info).ok())
+        .and_then(|info| info.get("pid")?.as_i64())
+        .map(|pid| pid as libc::pid_t)
+        .ok_or_else(|| "the runtime did not tell the pid of the container".to_string())
+}
+
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
@@ -0,0 +1,81 @@
+use async_trait::async_trait;
+use std::{fs, os::unix::fs::FileTypeExt, path::Path, time::Duration};
+
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    pub time: Option<Duration>,
+    pub memory: Option<u64>,
+    pub file: Option<u64>,
+    pub proc: Option<u64>,
+}
+
+impl Limits {
+    /// How long a run may take in real time before it is killed, leaving
+    /// room for programs that sleep or block on I/O.
+    pub fn wall_time(&self) -> Option<Duration> {
+        self.time.map(|time| time * 2 + Duration::from_secs(1))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Exceeded {
+    Time,
+    Memory,
+    Output,
+    Process,
+}
+
+#[derive(Default)]
+pub struct Execution {
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub cpu_time: Duration,
+    pub wall_time: Duration,
+    pub memory: u64,
+    pub exceeded: Option<Exceeded>,
+}
+
+impl Execution {
+    pub fn success(&self) -> bool {
+        self.exceeded.is_none() && self.exit_code == Some(0)
+    }
+}
+
+/// A backend able to run untrusted programs in isolation.
+#[async_trait]
+pub trait Executor: Send + Sync {
+    /// Sets up an isolated environment with an empty workspace for one
+    /// submission, based on the given container image where the backend
+    /// supports images.
+    async fn prepare(
+        &self,
+        name: &str,
+        image: Option<&str>,
+    ) -> Result<Box<dyn Environment>, String>;
+}
+
+/// The environment a submission is compiled and run in. Every path handed
+/// in is a host path, the workspace is visible to the programs as their
+/// working directory.
+#[async_trait]
+pub trait Environment: Send + Sync {
+    fn workspace(&self) -> &Path;
+
+    async fn run(
+        &self,
+        args: &[String],
+        limits: &Limits,
+        stdin: Option<&Path>,
+        stdout: &Path,
+        stderr: &Path,
+    ) -> Result<Execution, String>;
+
+    async fn cleanup(self: Box<Self>);
+}
+
+/// Whether the given stdin or stdout is a named pipe to another program, as
+/// in interactive stages. Such an stdout is opened for reading as well,
+/// before stdin, so that neither side waits on the other to open its end.
+pub fn is_pipe(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
@@ -0,0 +1,1184 @@
+use super::{
+    checker::{self, Outcome},
+    comparator::{Comparator, Comparison},
+    executor::{Environment, Exceeded, Execution, Executor, Limits},
+    language::{Language, Languages},
+    plan::{self, Plan, RunMode},
+    preset::Presets,
+    script::{self, Context, Hook, Report},
+    workspace,
+};
+use crate::{
+    metrics::METRICS,
+    schema::{
+        self, File, JudgeConfig, JudgeResult, Program, Stage, StageResult, Testcase, TestcaseEntry,
+        Verdict,
+    },
+    validate,
+};
+use futures::{
+    stream::{self, StreamExt},
+    FutureExt,
+};
+use nix::{sys::stat::Mode, unistd::mkfifo};
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    convert::TryFrom,
+    fs,
+    hash::{BuildHasher, Hasher},
+    io::{self, Read},
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{field, info, info_span, Instrument};
+
+const COMPILE_LIMITS: Limits = Limits {
+    time: Some(Duration::from_secs(10)),
+    memory: Some(1 << 30),
+    file: Some(64 << 20),
+    proc: Some(64),
+};
+// generators and reference programs are trusted, so they get as much room
+// as compilers do
+const HELPER_LIMITS: Limits = COMPILE_LIMITS;
+const DEFAULT_TIME: Duration = Duration::from_secs(10);
+const EXCERPT_LENGTH: u64 = 4096;
+
+/// Compiles a submission and runs its stages on whichever executor backend
+/// it was given.
+#[derive(Clone)]
+pub struct Judge {
+    data_dir: PathBuf,
+    executor: Arc<dyn Executor>,
+    languages: Arc<Languages>,
+    presets: Arc<Presets>,
+    parallelism: usize,
+}
+
+/// A trusted program of the problem, such as the random testcase generator,
+/// compiled in an environment of its own.
+struct Helper {
+    env: Box<dyn Environment>,
+    run: Vec<String>,
+}
+
+/// Everything the stages of a compiled submission are run with.
+struct Submission<'a> {
+    env: &'a dyn Environment,
+    config: &'a JudgeConfig,
+    language: &'a Language,
+    run: Vec<String>,
+    generator: Option<&'a Helper>,
+    reference: Option<&'a Helper>,
+    comparator: Option<&'a Helper>,
+    interactor: Option<&'a Helper>,
+}
+
+/// The files a stage is run on.
+#[derive(Default)]
+struct Testdata {
+    seed: Option<u64>,
+    input: Option<PathBuf>,
+    answer: Option<PathBuf>,
+}
+
+impl Judge {
+    pub fn new(
+        data_dir: PathBuf,
+        executor: Arc<dyn Executor>,
+        languages: Arc<Languages>,
+        presets: Arc<Presets>,
+        parallelism: usize,
+    ) -> Self {
+        Self {
+            data_dir,
+            executor,
+            languages,
+            presets,
+            parallelism: parallelism.max(1),
+        }
+    }
+
+    pub async fn judge(&self, config: &JudgeConfig) -> Result<JudgeResult, String> {
+        let language = match self.languages.get(&config.program.language) {
+            Some(language) => language,
+            None => {
+                return Ok(JudgeResult::compile_error(
+                    config.id,
+                    Some(format!("unsupported language {}", config.program.language)),
+                ))
+            }
+        };
+
+        let problems = validate::check(config);
+        if !problems.is_empty() {
+            let problems: Vec<String> = problems.iter().map(ToString::to_string).collect();
+            return Ok(JudgeResult::system_error(
+                config.id,
+                format!("invalid judge config: {}", problems.join("; ")),
+            ));
+        }
+
+        let config = &match self.presets.apply(config) {
+            Ok(config) => config,
+            Err(err) => return Ok(JudgeResult::system_error(config.id, err)),
+        };
+        let plan = match Plan::new(&config.stages) {
+            Ok(plan) => plan,
+            Err(err) => return Ok(JudgeResult::system_error(config.id, err)),
+        };
+
+        if plan.random() && config.random_generator.is_none() {
+            return Ok(JudgeResult::system_error(
+                config.id,
+                "random testcases require a random generator".to_string(),
+            ));
+        }
+        if plan.interactive() && config.interactor.is_none() {
+            return Ok(JudgeResult::system_error(
+                config.id,
+                "interactive stages require an interactor".to_string(),
+            ));
+        }
+
+        let env = self
+            .prepare(&config.id.to_string(), language.image.as_deref())
+            .await?;
+        let result = self.judge_in(env.as_ref(), config, language, &plan).await;
+        env.cleanup().await;
+
+        result
+    }
+
+    /// Sets up an environment, recording how long the executor took.
+    async fn prepare(
+        &self,
+        name: &str,
+        image: Option<&str>,
+    ) -> Result<Box<dyn Environment>, String> {
+        let started = Instant::now();
+        let env = self.executor.prepare(name, image).await;
+        METRICS.prepared(started.elapsed());
+        env
+    }
+
+    fn copy(&self, env: &dyn Environment, file: &File) -> Result<PathBuf, String> {
+        self.copy_as(env, file, relative_path(&file.path)?)
+    }
+
+    fn copy_as(&self, env: &dyn Environment, file: &File, name: &Path) -> Result<PathBuf, String> {
+        let relative = relative_path(&file.path)?;
+        let target = env.workspace().join(name);
+        fs::File::open(self.data_dir.join(relative))
+            .and_then(|mut source| {
+                let mut copy = workspace::create(env.workspace(), &target)?;
+                io::copy(&mut source, &mut copy)?;
+                copy.set_permissions(source.metadata()?.permissions())
+            })
+            .map_err(|err| format!("failed to copy {}: {}", file.path, err))?;
+
+        Ok(target)
+    }
+
+    async fn compile(
+        &self,
+        env: &dyn Environment,
+        role: &str,
+        program: &Program,
+        language: &Language,
+    ) -> Result<Option<Vec<String>>, String> {
+        // the entry source goes first, stored under the name the language
+        // expects if it has one
+        let entry = program
+            .entry_point
+            .as_ref()
+            .and_then(|entry| program.sources.iter().position(|file| &file.path == entry))
+            .unwrap_or(0);
+        let mut sources = Vec::new();
+        for (index, source) in program.sources.iter().enumerate() {
+            let name = match &language.source {
+                Some(name) if index == entry => Path::new(name),
+                _ => relative_path(&source.path)?,
+            };
+            self.copy_as(env, source, name)?;
+            let name = name.to_string_lossy().into_owned();
+            if index == entry {
+                sources.insert(0, name);
+            } else {
+                sources.push(name);
+            }
+        }
+
+        if let Some(compile) = &language.compile {
+            let compile = Language::expand(compile, &sources, &program.compile_args);
+            let stdout = env.workspace().join("compile.out");
+            let stderr = env.workspace().join("compile.err");
+            let span = info_span!(
+                "compile",
+                program = role,
+                language = %program.language,
+                succeeded = field::Empty,
+            );
+            let execution = env
+                .run(&compile, &COMPILE_LIMITS, None, &stdout, &stderr)
+                .instrument(span.clone())
+                .await?;
+            span.record("succeeded", execution.success());
+            if !execution.success() {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(Language::expand(&language.run, &sources, &[])))
+    }
+
+    /// Compiles a helper program in a fresh environment, which the caller has
+    /// to clean up.
+    async fn helper(
+        &self,
+        config: &JudgeConfig,
+        role: &str,
+        program: &Program,
+    ) -> Result<Helper, String> {
+        let language = self
+            .languages
+            .get(&program.language)
+            .ok_or_else(|| format!("unsupported language {} of the {}", program.language, role))?;
+        let env = self
+            .prepare(
+                &format!("{}-{}", config.id, role),
+                language.image.as_deref(),
+            )
+            .await?;
+
+        match self.compile(env.as_ref(), role, program, language).await {
+            Ok(Some(run)) => Ok(Helper { env, run }),
+            Ok(None) => {
+                let message = excerpt(env.workspace(), &env.workspace().join("compile.err"))
+                    .unwrap_or_default();
+                env.cleanup().await;
+                Err(format!("the {} failed to compile: {}", role, message))
+            }
+            Err(err) => {
+                env.cleanup().await;
+                Err(err)
+            }
+        }
+    }
+
+    /// Runs the generator with the seed of the testcase, or a fresh one, and
+    /// the reference program on the generated input if there is one.
+    async fn generate(
+        &self,
+        submission: &Submission<'_>,
+        key: &str,
+        entry: &TestcaseEntry,
+    ) -> Result<(u64, PathBuf, Option<PathBuf>), String> {
+        let generator = submission.generator.unwrap();
+        let seed = entry.seed.unwrap_or_else(random_seed);
+
+        let mut args = generator.run.clone();
+        args.push(seed.to_string());
+        let input = generator.env.workspace().join(format!("random-{}.in", key));
+        let stderr = generator
+            .env
+            .workspace()
+            .join(format!("random-{}.err", key));
+        let execution = generator
+            .env
+            .run(&args, &HELPER_LIMITS, None, &input, &stderr)
+            .await?;
+        if !execution.success() {
+            return Err(format!(
+                "the generator failed with seed {}: {}",
+                seed,
+                excerpt(generator.env.workspace(), &stderr).unwrap_or_default()
+            ));
+        }
+
+        let answer = match submission.reference {
+            Some(reference) => {
+                let answer = reference
+                    .env
+                    .workspace()
+                    .join(format!("random-{}.ans", key));
+                let stderr = reference
+                    .env
+                    .workspace()
+                    .join(format!("random-{}.err", key));
+                let execution = reference
+                    .env
+                    .run(
+                        &reference.run,
+                        &HELPER_LIMITS,
+                        Some(&input),
+                        &answer,
+                        &stderr,
+                    )
+                    .await?;
+                if !execution.success() {
+                    return Err(format!(
+                        "the reference program failed with seed {}: {}",
+                        seed,
+                        excerpt(reference.env.workspace(), &stderr).unwrap_or_default()
+                    ));
+                }
+                Some(answer)
+            }
+            None => None,
+        };
+
+        Ok((seed, input, answer))
+    }
+
+    /// Has the custom comparator judge the output of a stage, handing it
+    /// copies of the input, the output and the answer, empty if missing.
+    async fn check(
+        &self,
+        submission: &Submission<'_>,
+        comparator: &Helper,
+        key: &str,
+        stage: &Stage,
+        testdata: &Testdata,
+        output: &Path,
+    ) -> Result<Outcome, String> {
+        let workspace = comparator.env.workspace();
+        let files = [
+            (format!("check-{}.in", key), testdata.input.as_deref()),
+            (format!("check-{}.out", key), Some(output)),
+            (format!("check-{}.ans", key), testdata.answer.as_deref()),
+        ];
+        let mut args = comparator.run.clone();
+        for (name, source) in files.iter() {
+            hand_over(submission.env.workspace(), workspace, name, *source)
+                .map_err(|err| format!("failed to prepare {}: {}", name, err))?;
+            args.push(name.clone());
+        }
+
+        let stdout = workspace.join(format!("check-{}.stdout", key));
+        let stderr = workspace.join(format!("check-{}.stderr", key));
+        let span = info_span!("checker", checker = "comparator", verdict = field::Empty);
+        let execution = comparator
+            .env
+            .run(&args, &HELPER_LIMITS, None, &stdout, &stderr)
+            .instrument(span.clone())
+            .await?;
+
+        let outcome = checker::interpret(
+            "comparator",
+            &execution,
+            &workspace::read_to_string(workspace, &stdout).unwrap_or_default(),
+            excerpt(workspace, &stderr),
+            stage.grade,
+        )?;
+        span.record("verdict", outcome.status.to_string().as_str());
+        Ok(outcome)
+    }
+
+    /// Resolves the input and answer of a stage, generating them for a
+    /// random testcase.
+    async fn testdata(
+        &self,
+        submission: &Submission<'_>,
+        key: &str,
+        stage: &Stage,
+    ) -> Result<Testdata, String> {
+        let mut testdata = Testdata {
+            seed: None,
+            input: None,
+            answer: None,
+        };
+        let entry = match &stage.testcase {
+            Some(entry) => entry,
+            None => return Ok(testdata),
+        };
+        let random = entry.is_random == Some(true);
+        let span = info_span!("testcase", testcase = entry.id, random, seed = field::Empty);
+        if random {
+            let (seed, input, answer) = self
+                .generate(submission, key, entry)
+                .instrument(span.clone())
+                .await?;
+            span.record("seed", seed);
+            testdata.seed = Some(seed);
+            testdata.input = Some(input);
+            testdata.answer = answer;
+        } else {
+            let _entered = span.enter();
+            for file in &testcase(submission.config, entry.id)?.sources {
+                match file.r#type.as_deref() {
+                    Some("input") => {
+                        testdata.input = Some(self.data_dir.join(relative_path(&file.path)?))
+                    }
+                    Some("output") | Some("answer") => {
+                        testdata.answer = Some(self.data_dir.join(relative_path(&file.path)?))
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        Ok(testdata)
+    }
+
+    /// Copies the files of the testcase of a stage other than its input and
+    /// answer into the workspace, under their paths in the data directory.
+    fn copy_testcase_files(
+        &self,
+        submission: &Submission<'_>,
+        stage: &Stage,
+    ) -> Result<(), String> {
+        let entry = match &stage.testcase {
+            Some(entry) if entry.is_random != Some(true) => entry,
+            _ => return Ok(()),
+        };
+        for file in &testcase(submission.config, entry.id)?.sources {
+            if !matches!(
+                file.r#type.as_deref(),
+                Some("input") | Some("output") | Some("answer")
+            ) {
+                self.copy(submission.env, file)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn run_stage(
+        &self,
+        submission: &Submission<'_>,
+        key: &str,
+        stage: &Stage,
+        parent: Option<&Stage>,
+    ) -> Result<StageResult, String> {
+        let span = info_span!(
+            "stage",
+            stage = %stage.name,
+            key,
+            verdict = field::Empty,
+            score = field::Empty,
+            error = field::Empty,
+        );
+        let result = self
+            .run_stage_in(submission, key, stage, parent)
+            .instrument(span.clone())
+            .await;
+        match &result {
+            Ok(result) => {
+                span.record("verdict", result.status.to_string().as_str());
+                span.record("score", result.score);
+            }
+            Err(err) => {
+                span.record("error", err.as_str());
+            }
+        }
+        result
+    }
+
+    async fn run_stage_in(
+        &self,
+        submission: &Submission<'_>,
+        key: &str,
+        stage: &Stage,
+        parent: Option<&Stage>,
+    ) -> Result<StageResult, String> {
+        let env = submission.env;
+        let limits = limits(
+            stage
+                .limits
+                .as_ref()
+                .or_else(|| parent.and_then(|parent| parent.limits.as_ref())),
+            Some(submission.language),
+        );
+        let mode = plan::run_mode(stage, parent)?;
+        let testdata = if mode == RunMode::Never {
+            Testdata::default()
+        } else {
+            // replicas have theirs copied before they start side by side
+            if parent.is_none() {
+                self.copy_testcase_files(submission, stage)?;
+            }
+            self.testdata(submission, key, stage).await?
+        };
+
+        let (execution, outcome) = match mode {
+            // the program compiled, which is all the stage asks for
+            RunMode::Never => (
+                Execution::default(),
+                Outcome {
+                    status: Verdict::Accepted,
+                    score: stage.grade,
+                    message: None,
+                },
+            ),
+            RunMode::Batch => {
+                self.run_batch(submission, key, stage, parent, &limits, &testdata)
+                    .await?
+            }
+            RunMode::Interactive => {
+                self.interact(submission, key, stage, parent, &limits, &testdata)
+                    .await?
+            }
+            RunMode::Script => {
+                self.run_script(submission, key, stage, parent, &limits, &testdata)
+                    .await?
+            }
+        };
+        info!(
+            exit_code = ?execution.exit_code,
+            signal = ?execution.signal,
+            time = execution.cpu_time.as_millis() as u64,
+            wall_time = execution.wall_time.as_millis() as u64,
+            memory = execution.memory,
+            "stage exited."
+        );
+
+        let workspace = env.workspace();
+        let output =
+            Some(workspace.join(format!("stage-{}.out", key))).filter(|_| mode == RunMode::Batch);
+        let outcome = match Hook::Check.of_stage(stage, parent) {
+            Some(source) => {
+                let mut context = context(env, stage, &testdata, output.clone());
+                context.result = Some(script::result(&outcome, &execution));
+                let (report, _) = self
+                    .script(submission, key, Hook::Check, source, &limits, context)
+                    .await?;
+                report.apply(outcome, stage.grade)
+            }
+            None => outcome,
+        };
+
+        Ok(StageResult {
+            name: stage.name.clone(),
+            testcase: stage.testcase.as_ref().map(|entry| entry.id),
+            seed: testdata.seed,
+            status: outcome.status,
+            score: outcome.score,
+            time: execution.cpu_time.as_millis() as u64,
+            wall_time: execution.wall_time.as_millis() as u64,
+            memory: execution.memory,
+            exit_code: execution.exit_code,
+            signal: execution.signal,
+            // only batch runs leave their output behind, the one of an
+            // interactive stage went to the interactor
+            stdout: output
+                .as_deref()
+                .and_then(|output| excerpt(workspace, output)),
+            stderr: excerpt(workspace, &workspace.join(format!("stage-{}.err", key))),
+            message: outcome.message,
+            replicas: None,
+        })
+    }
+
+    /// Runs the program on the input of a stage and compares its output.
+    async fn run_batch(
+        &self,
+        submission: &Submission<'_>,
+        key: &str,
+        stage: &Stage,
+        parent: Option<&Stage>,
+        limits: &Limits,
+        testdata: &Testdata,
+    ) -> Result<(Execution, Outcome), String> {
+        let env = submission.env;
+        let stdout = env.workspace().join(format!("stage-{}.out", key));
+        let stderr = env.workspace().join(format!("stage-{}.err", key));
+        let execution = env
+            .run(
+                &submission.run,
+                limits,
+                testdata.input.as_deref(),
+                &stdout,
+                &stderr,
+            )
+            .await?;
+
+        // a built-in comparator asked for by the stage takes precedence over
+        // the custom comparator of the problem
+        let comparator = Comparator::of_stage(stage, parent)?;
+        let outcome = match (
+            execution_verdict(&execution),
+            Hook::Compare.of_stage(stage, parent),
+            comparator,
+            submission.comparator,
+        ) {
+            (Some(status), _, _, _) => Outcome {
+                status,
+                score: 0,
+                message: None,
+            },
+            (None, Some(source), _, _) => {
+                let context = context(env, stage, testdata, Some(stdout.clone()));
+                let (report, _) = self
+                    .script(submission, key, Hook::Compare, source, limits, context)
+                    .await?;
+                if !report.decided() {
+                    return Err(
+                        "the compare script neither evaluated to a bool nor set a verdict"
+                            .to_string(),
+                    );
+                }
+                report.apply(
+                    Outcome {
+                        status: Verdict::Accepted,
+                        score: stage.grade,
+                        message: None,
+                    },
+                    stage.grade,
+                )
+            }
+            (None, None, None, Some(custom)) => {
+                self.check(submission, custom, key, stage, testdata, &stdout)
+                    .await?
+            }
+            (None, None, comparator, _) => {
+                let comparison = match &testdata.answer {
+                    Some(answer) => {
+                        comparator
+                            .unwrap_or_default()
+                            .compare(env.workspace(), answer, &stdout)?
+                    }
+                    // a check script has the last word on the output anyway
+                    None if Hook::Check.of_stage(stage, parent).is_some() => Comparison {
+                        status: Verdict::Accepted,
+                        mismatch: None,
+                    },
+                    None => {
+                        return Err(format!(
+                            "stage {} has neither an answer nor a comparator to judge its output",
+                            stage.name
+                        ))
+                    }
+                };
+                Outcome {
+                    status: comparison.status,
+                    score: if comparison.status == Verdict::Accepted {
+                        stage.grade
+                    } else {
+                        0
+                    },
+                    message: comparison.mismatch,
+                }
+            }
+        };
+
+        Ok((execution, outcome))
+    }
+
+    /// Has the run script of a stage run the program, the first run that
+    /// failed deciding the verdict unless the script decides otherwise.
+    async fn run_script(
+        &self,
+        submission: &Submission<'_>,
+        key: &str,
+        stage: &Stage,
+        parent: Option<&Stage>,
+        limits: &Limits,
+        testdata: &Testdata,
+    ) -> Result<(Execution, Outcome), String> {
+        let source = Hook::Run.of_stage(stage, parent).unwrap();
+        let context = context(submission.env, stage, testdata, None);
+        let (report, runs) = self
+            .script(submission, key, Hook::Run, source, limits, context)
+            .await?;
+
+        let status = runs
+            .iter()
+            .find_map(execution_verdict)
+            .unwrap_or(Verdict::Accepted);
+        let outcome = Outcome {
+            status,
+            score: if status == Verdict::Accepted {
+                stage.grade
+            } else {
+                0
+            },
+            message: None,
+        };
+        Ok((combine(&runs), report.apply(outcome, stage.grade)))
+    }
+
+    /// Evaluates a script of a stage, running the program within the limits
+    /// of the stage whenever the script asks to. Returns what the script
+    /// decided along with the runs it made.
+    async fn script(
+        &self,
+        submission: &Submission<'_>,
+        key: &str,
+        hook: Hook,
+        source: &str,
+        limits: &Limits,
+        context: Context,
+    ) -> Result<(Report, Vec<Execution>), String> {
+        self.script_in(submission, key, hook, source, limits, context)
+            .instrument(info_span!("script", hook = hook.name()))
+            .await
+    }
+
+    async fn script_in(
+        &self,
+        submission: &Submission<'_>,
+        key: &str,
+        hook: Hook,
+        source: &str,
+        limits: &Limits,
+        context: Context,
+    ) -> Result<(Report, Vec<Execution>), String> {
+        let env = submission.env;
+        let mut session = script::start(hook, source, context);
+        let mut runs = Vec::new();
+        while let Some(request) = session.requests.next().await {
+            let name = format!("{}-{}-{}", hook.name(), key, runs.len());
+            let (stdout, stderr) = (format!("{}.out", name), format!("{}.err", name));
+            let mut args = submission.run.clone();
+            args.extend(request.args);
+            let result = env
+                .run(
+                    &args,
+                    limits,
+                    request.stdin.as_deref(),
+                    &env.workspace().join(&stdout),
+                    &env.workspace().join(&stderr),
+                )
+                .await
+                .map(|execution| {
+                    let status = execution_verdict(&execution).unwrap_or(Verdict::Accepted);
+                    let run =
+                        script::run(&execution, status, Path::new(&stdout), Path::new(&stderr));
+                    runs.push(execution);
+                    run
+                });
+            // a script that failed in the meantime no longer listens
+            let _ = request.reply.send(result);
+        }
+
+        Ok((session.finish().await?, runs))
+    }
+
+    /// Runs the program and the interactor side by side, connected by a pair
+    /// of FIFOs linked into both workspaces. The interactor is run as
+    /// `<run> <input> <answer> <report>` and judges the program like a custom
+    /// comparator would, through its exit code and the report file.
+    ///
+    /// Whichever side stopped first is to blame: a program that fails before
+    /// the interactor finished gets its own verdict, while one that is left
+    /// blocked after the interactor rejected it gets the verdict of the
+    /// interactor. Both running out of time means they waited on each other.
+    async fn interact(
+        &self,
+        submission: &Submission<'_>,
+        key: &str,
+        stage: &Stage,
+        parent: Option<&Stage>,
+        limits: &Limits,
+        testdata: &Testdata,
+    ) -> Result<(Execution, Outcome), String> {
+        let (env, interactor) = (submission.env, submission.interactor.unwrap());
+        let workspace = interactor.env.workspace();
+        // named after the direction of the data, seen from the program
+        let (to_program, from_program) = (
+            format!("interact-{}.in", key),
+            format!("interact-{}.out", key),
+        );
+        for name in [&to_program, &from_program].iter() {
+            let path = env.workspace().join(name);
+            mkfifo(&path, Mode::from_bits_truncate(0o600))
+                .map_err(|err| err.to_string())
+                .and_then(|_| {
+                    // both sides may run as users of their own, and the pipe
+                    // is changed through a descriptor since the program may
+                    // have replaced it by now
+                    workspace::open_pipe(env.workspace(), &path, true)
+                        .and_then(|pipe| pipe.set_permissions(fs::Permissions::from_mode(0o666)))
+                        .and_then(|_| fs::hard_link(&path, workspace.join(name)))
+                        .map_err(|err| err.to_string())
+                })
+                .map_err(|err| format!("failed to create {}: {}", name, err))?;
+        }
+
+        let mut args = interactor.run.clone();
+        let files = [
+            (format!("interact-{}.input", key), testdata.input.as_deref()),
+            (
+                format!("interact-{}.answer", key),
+                testdata.answer.as_deref(),
+            ),
+        ];
+        for (name, source) in files.iter() {
+            hand_over(env.workspace(), workspace, name, *source)
+                .map_err(|err| format!("failed to prepare {}: {}", name, err))?;
+            args.push(name.clone());
+        }
+        let report = format!("interact-{}.report", key);
+        args.push(report.clone());
+        let interactor_limits = limits_of_interactor(stage, parent);
+
+        let (stdin, stdout) = (
+            env.workspace().join(&to_program),
+            env.workspace().join(&from_program),
+        );
+        let stderr = env.workspace().join(format!("stage-{}.err", key));
+        let (interactor_stdin, interactor_stdout) =
+            (workspace.join(&from_program), workspace.join(&to_program));
+        let interactor_stderr = workspace.join(format!("interact-{}.err", key));
+        // each side waits for the other to open its stdout before it reads
+        // its stdin, so when one fails before getting that far, the pipes
+        // are opened once here to let the other one through to an EOF
+        let unblock = |result: &Result<Execution, String>| {
+            if result.is_err() {
+                for name in [&to_program, &from_program].iter() {
+                    let _ =
+                        workspace::open_pipe(env.workspace(), &env.workspace().join(name), true);
+                }
+            }
+        };
+        let span = info_span!("checker", checker = "interactor", verdict = field::Empty);
+        let ((execution, program_exited), (interaction, interactor_exited)) = futures::join!(
+            env.run(&submission.run, limits, Some(&stdin), &stdout, &stderr)
+                .inspect(unblock)
+                .map(|result| (result, Instant::now())),
+            interactor
+                .env
+                .run(
+                    &args,
+                    &interactor_limits,
+                    Some(&interactor_stdin),
+                    &interactor_stdout,
+                    &interactor_stderr,
+                )
+                .inspect(unblock)
+                .map(|result| (result, Instant::now()))
+                .instrument(span.clone())
+        );
+        let (execution, interaction) = (execution?, interaction?);
+
+        if execution.exceeded == Some(Exceeded::Time)
+            && interaction.exceeded == Some(Exceeded::Time)
+        {
+            return Ok((
+                execution,
+                Outcome {
+                    status: Verdict::TimeLimitExceeded,
+                    score: 0,
+                    message: Some(
+                        "the program and the interactor waited on each other".to_string(),
+                    ),
+                },
+            ));
+        }
+        let outcome = checker::interpret(
+            "interactor",
+            &interaction,
+            &workspace::read_to_string(workspace, &workspace.join(&report)).unwrap_or_default(),
+            excerpt(workspace, &interactor_stderr),
+            stage.grade,
+        )?;
+        span.record("verdict", outcome.status.to_string().as_str());
+
+        // an interactor that gave up on the program first explains whatever
+        // the program did next, such as dying of writing to the closed pipe
+        let interactor_first =
+            interactor_exited < program_exited || execution.signal == Some(libc::SIGPIPE);
+        let outcome = match execution_verdict(&execution) {
+            Some(status) if !(interactor_first && outcome.status != Verdict::Accepted) => Outcome {
+                status,
+                score: 0,
+                message: None,
+            },
+            _ => outcome,
+        };
+
+        Ok((execution, outcome))
+    }
+
+    /// Runs the replicas of a stage, at most `parallelism` at once, each
+    /// falling back to the limits and comparator of the stage.
+    async fn run_replicas(
+        &self,
+        submission: &Submission<'_>,
+        index: usize,
+        stage: &Stage,
+    ) -> StageResult {
+        let mut runs = Vec::new();
+        let replicas = stage.replicas.as_deref().unwrap_or_default();
+        for (replica_index, replica) in replicas.iter().enumerate() {
+            let key = format!("{}-{}", index, replica_index);
+            // the replicas share the workspace, so the files of their
+            // testcases are in place before any of them runs
+            let copied = self.copy_testcase_files(submission, replica);
+            runs.push(async move {
+                match copied {
+                    Ok(()) => self.run_stage(submission, &key, replica, Some(stage)).await,
+                    Err(err) => Err(err),
+                }
+                .unwrap_or_else(|err| StageResult::system_error(replica, err))
+            });
+        }
+        let span = info_span!(
+            "stage",
+            stage = %stage.name,
+            replicas = replicas.len(),
+            verdict = field::Empty,
+            score = field::Empty,
+        );
+        let results = stream::iter(runs)
+            .buffered(self.parallelism)
+            .collect()
+            .instrument(span.clone())
+            .await;
+
+        let result = StageResult::from_replicas(stage, results);
+        span.record("verdict", result.status.to_string().as_str());
+        span.record("score", result.score);
+        result
+    }
+
+    async fn judge_in(
+        &self,
+        env: &dyn Environment,
+        config: &JudgeConfig,
+        language: &Language,
+        plan: &Plan,
+    ) -> Result<JudgeResult, String> {
+        let run = match self
+            .compile(env, "submission", &config.program, language)
+            .await?
+        {
+            Some(run) => run,
+            None => {
+                return Ok(JudgeResult::compile_error(
+                    config.id,
+                    excerpt(env.workspace(), &env.workspace().join("compile.err")),
+                ))
+            }
+        };
+
+        let random = plan.random();
+        let wanted = [
+            (
+                "generator",
+                config.random_generator.as_ref().filter(|_| random),
+            ),
+            (
+                "reference",
+                config.reference_program.as_ref().filter(|_| random),
+            ),
+            ("comparator", config.custom_comparator.as_ref()),
+            (
+                "interactor",
+                config.interactor.as_ref().filter(|_| plan.interactive()),
+            ),
+        ];
+        let mut helpers = HashMap::new();
+        let mut failure = None;
+        for (role, program) in wanted.iter() {
+            if let Some(program) = program {
+                match self.helper(config, role, program).await {
+                    Ok(helper) => {
+                        helpers.insert(*role, helper);
+                    }
+                    Err(err) => {
+                        failure = Some(err);
+                        break;
+                    }
+                }
+            }
+        }
+
+        let result = match failure {
+            Some(err) => JudgeResult::system_error(config.id, err),
+            None => {
+                let submission = Submission {
+                    env,
+                    config,
+                    language,
+                    run,
+                    generator: helpers.get("generator"),
+                    reference: helpers.get("reference"),
+                    comparator: helpers.get("comparator"),
+                    interactor: helpers.get("interactor"),
+                };
+                self.run_stages(&submission, plan).await
+            }
+        };
+
+        for (_, helper) in helpers {
+            helper.env.cleanup().await;
+        }
+
+        Ok(result)
+    }
+
+    async fn run_stages(&self, submission: &Submission<'_>, plan: &Plan) -> JudgeResult {
+        let config = submission.config;
+        let mut stages: Vec<Option<StageResult>> = config.stages.iter().map(|_| None).collect();
+        for &index in plan.order() {
+            let stage = &config.stages[index];
+            let result = match plan.requirement(index) {
+                Some((on, cond)) => {
+                    // the plan orders every stage after the one it requires
+                    let required = stages[on].as_ref().unwrap();
+                    match cond.map_or(Ok(true), |cond| cond.eval(required)) {
+                        _ if required.status == Verdict::Skipped => Err(StageResult::skipped(
+                            stage,
+                            format!("required stage {} was skipped", required.name),
+                        )),
+                        Ok(true) => Ok(()),
+                        Ok(false) => Err(StageResult::skipped(
+                            stage,
+                            format!("condition on stage {} is not met", required.name),
+                        )),
+                        Err(err) => Err(StageResult::system_error(stage, err)),
+                    }
+                }
+                None => Ok(()),
+            };
+
+            stages[index] = Some(match result {
+                Ok(()) if stage.replicas.is_some() => {
+                    self.run_replicas(submission, index, stage).await
+                }
+                Ok(()) => self
+                    .run_stage(submission, &index.to_string(), stage, None)
+                    .await
+                    .unwrap_or_else(|err| StageResult::system_error(stage, err)),
+                Err(result) => result,
+            });
+        }
+
+        JudgeResult::from_stages(config.id, stages.into_iter().flatten().collect())
+    }
+}
+
+fn testcase(config: &JudgeConfig, id: i32) -> Result<&Testcase, String> {
+    config
+        .testcases
+        .iter()
+        .find(|testcase| testcase.id == id)
+        .ok_or_else(|| format!("testcase {} does not exist", id))
+}
+
+fn random_seed() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// The verdict of a run that did not even get to have its output compared.
+fn execution_verdict(execution: &Execution) -> Option<Verdict> {
+    match execution.exceeded {
+        Some(Exceeded::Time) => Some(Verdict::TimeLimitExceeded),
+        Some(Exceeded::Memory) => Some(Verdict::MemoryLimitExceeded),
+        Some(Exceeded::Output) => Some(Verdict::OutputLimitExceeded),
+        Some(Exceeded::Process) => Some(Verdict::ProcessLimitExceeded),
+        None if !execution.success() => Some(Verdict::RuntimeError),
+        None => None,
+    }
+}
+
+/// Sums up the runs a script made as if they were one.
+fn combine(runs: &[Execution]) -> Execution {
+    Execution {
+        exit_code: runs.last().and_then(|run| run.exit_code),
+        signal: runs.last().and_then(|run| run.signal),
+        cpu_time: runs.iter().map(|run| run.cpu_time).sum(),
+        wall_time: runs.iter().map(|run| run.wall_time).sum(),
+        memory: runs.iter().map(|run| run.memory).max().unwrap_or(0),
+        exceeded: runs.iter().find_map(|run| run.exceeded),
+    }
+}
+
+fn context(
+    env: &dyn Environment,
+    stage: &Stage,
+    testdata: &Testdata,
+    output: Option<PathBuf>,
+) -> Context {
+    Context {
+        workspace: env.workspace().to_path_buf(),
+        input: testdata.input.clone(),
+        output,
+        answer: testdata.answer.clone(),
+        result: None,
+        grade: stage.grade,
+    }
+}
+
+/// Copies a file of a stage, which may be below the workspace of the program,
+/// into the workspace of a helper, or leaves an empty file in its place if the
+/// stage has none.
+fn hand_over(from: &Path, to: &Path, name: &str, source: Option<&Path>) -> io::Result<()> {
+    let mut target = workspace::create(to, &to.join(name))?;
+    if let Some(source) = source {
+        io::copy(&mut workspace::open(from, source)?, &mut target)?;
+    }
+    Ok(())
+}
+
+/// Reads at most `EXCERPT_LENGTH` bytes of the given file below a workspace
+/// for the result.
+fn excerpt(workspace: &Path, path: &Path) -> Option<String> {
+    let mut content = Vec::new();
+    workspace::open(workspace, path)
+        .ok()?
+        .take(EXCERPT_LENGTH)
+        .read_to_end(&mut content)
+        .ok()?;
+
+    if content.is_empty() {
+        None
+    } else {
+        Some(String::from_utf8_lossy(&content).into_owned())
+    }
+}
+
+/// The limits of the interactor of a stage, falling back to the ones of its
+/// stage and then to the limits of the program.
+fn limits_of_interactor(stage: &Stage, parent: Option<&Stage>) -> Limits {
+    limits(
+        stage
+            .interactor_limits
+            .as_ref()
+            .or_else(|| parent.and_then(|parent| parent.interactor_limits.as_ref()))
+            .or(stage.limits.as_ref())
+            .or_else(|| parent.and_then(|parent| parent.limits.as_ref())),
+        None,
+    )
+}
+
+fn limits(limits: Option<&schema::Limits>, language: Option<&Language>) -> Limits {
+    let value = |value: Option<i64>| value.and_then(|value| u64::try_from(value).ok());
+
+    let limits = match limits {
+        Some(limits) => Limits {
+            time: Some(value(limits.time).map_or(DEFAULT_TIME, Duration::from_millis)),
+            memory: value(limits.memory),
+            file: value(limits.file),
+            proc: value(limits.proc),
+        },
+        None => Limits {
+            time: Some(DEFAULT_TIME),
+            ..Limits::default()
+        },
+    };
+
+    match language {
+        Some(language) => language.extend(limits),
+        None => limits,
+    }
+}
+
+fn relative_path(path: &str) -> Result<&Path, String> {
+    let path = Path::new(path);
+    if path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        Ok(path)
+    } else {
+        Err(format!(
+            "file path {} escapes the data directory",
+            path.display()
+        ))
+    }
+}
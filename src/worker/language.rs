@@ -0,0 +1,188 @@
+use super::executor::Limits;
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::Path, time::Duration};
+
+const BUILTIN: &str = include_str!("../../languages.toml");
+
+#[derive(Deserialize)]
+pub struct Language {
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// The name the entry source is stored under, e.g. `Main.java`
+    pub source: Option<String>,
+    pub compile: Option<Vec<String>>,
+    pub run: Vec<String>,
+    /// Extra CPU time in milliseconds granted to every run
+    #[serde(default)]
+    pub extra_time: u64,
+    /// Extra memory in bytes granted to every run
+    #[serde(default)]
+    pub extra_memory: u64,
+    /// The container image used by the cri executor
+    pub image: Option<String>,
+}
+
+impl Language {
+    /// Grants the extra time and memory of the language on top of the limits
+    /// of a run, leaving those it has none of unlimited.
+    pub fn extend(&self, limits: Limits) -> Limits {
+        Limits {
+            time: limits
+                .time
+                .map(|time| time + Duration::from_millis(self.extra_time)),
+            memory: limits.memory.map(|memory| memory + self.extra_memory),
+            ..limits
+        }
+    }
+
+    /// Expands a command template for a program whose sources were stored at
+    /// the given paths, the first being the entry source.
+    pub fn expand(template: &[String], sources: &[String], args: &[String]) -> Vec<String> {
+        let source = sources.first().map(String::as_str).unwrap_or_default();
+        template
+            .iter()
+            .flat_map(|arg| match arg.as_str() {
+                "{sources}" => sources.to_vec(),
+                "{args}" => args.to_vec(),
+                _ => vec![arg.replace("{source}", source)],
+            })
+            .collect()
+    }
+}
+
+/// The languages submissions may be written in, keyed by name and alias.
+pub struct Languages {
+    languages: HashMap<String, Language>,
+    aliases: HashMap<String, String>,
+}
+
+impl Languages {
+    pub fn builtin() -> Self {
+        Self::parse(BUILTIN, false).unwrap()
+    }
+
+    /// Loads a registry from a TOML file, or a JSON one if the file name ends
+    /// with `.json`.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        let json = path
+            .extension()
+            .is_some_and(|extension| extension == "json");
+        Self::parse(&content, json).map_err(|err| format!("invalid {}: {}", path.display(), err))
+    }
+
+    fn parse(content: &str, json: bool) -> Result<Self, String> {
+        let languages: HashMap<String, Language> = if json {
+            serde_json::from_str(content).map_err(|err| err.to_string())?
+        } else {
+            toml::from_str(content).map_err(|err| err.to_string())?
+        };
+
+        let mut aliases = HashMap::new();
+        for (name, language) in &languages {
+            for alias in &language.aliases {
+                if languages.contains_key(alias)
+                    || aliases.insert(alias.clone(), name.clone()).is_some()
+                {
+                    return Err(format!("language {} is defined twice", alias));
+                }
+            }
+        }
+
+        Ok(Self { languages, aliases })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Language> {
+        self.languages.get(name).or_else(|| {
+            self.aliases
+                .get(name)
+                .and_then(|name| self.languages.get(name))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn expands_templates() {
+        let languages = Languages::builtin();
+        let sources = strings(&["main.c", "lib.c"]);
+        let args = strings(&["-DONLINE", "-Wall"]);
+
+        let c = languages.get("c").unwrap();
+        assert_eq!(
+            Language::expand(c.compile.as_ref().unwrap(), &sources, &args),
+            strings(&[
+                "gcc", "-O2", "-std=c11", "-o", "main", "main.c", "lib.c", "-lm", "-DONLINE",
+                "-Wall"
+            ])
+        );
+        let rust = languages.get("rust").unwrap();
+        assert_eq!(
+            Language::expand(rust.compile.as_ref().unwrap(), &sources, &[]),
+            strings(&["rustc", "-O", "-o", "main", "main.c"])
+        );
+        let python = languages.get("python").unwrap();
+        assert_eq!(
+            Language::expand(&python.run, &strings(&["a.py"]), &[]),
+            strings(&["python3", "a.py"])
+        );
+        assert_eq!(
+            Language::expand(&strings(&["run", "--file={source}"]), &sources, &[]),
+            strings(&["run", "--file=main.c"])
+        );
+    }
+
+    #[test]
+    fn looks_up_aliases() {
+        let languages = Languages::builtin();
+        assert_eq!(
+            languages.get("c++").unwrap().compile,
+            languages.get("cpp").unwrap().compile
+        );
+        assert_eq!(
+            languages.get("python3").unwrap().run,
+            strings(&["python3", "{source}"])
+        );
+        assert!(languages.get("brainfuck").is_none());
+
+        assert_eq!(
+            Languages::parse("[c]\nrun = []\n[cpp]\naliases = [\"c\"]\nrun = []\n", false)
+                .err()
+                .unwrap(),
+            "language c is defined twice"
+        );
+    }
+
+    #[test]
+    fn extends_limits() {
+        let languages = Languages::builtin();
+        let limits = Limits {
+            time: Some(Duration::from_millis(1000)),
+            memory: Some(256 << 20),
+            file: Some(1 << 20),
+            proc: Some(1),
+        };
+
+        let java = languages.get("java").unwrap().extend(limits);
+        assert_eq!(java.time, Some(Duration::from_millis(2000)));
+        assert_eq!(java.memory, Some(512 << 20));
+        assert_eq!(java.file, Some(1 << 20));
+        assert_eq!(java.proc, Some(1));
+
+        let c = languages.get("c").unwrap().extend(limits);
+        assert_eq!(c.time, Some(Duration::from_millis(1000)));
+        assert_eq!(c.memory, Some(256 << 20));
+
+        let python = languages.get("python").unwrap().extend(Limits::default());
+        assert_eq!(python.time, None);
+        assert_eq!(python.memory, None);
+    }
+}
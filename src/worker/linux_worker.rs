@@ -1,22 +1,21 @@
-use super::worker::PlatformWorker;
+use super::{judge::Judge, worker::PlatformWorker};
 use crate::{schema::JudgeConfig, JudgeResult};
 use async_trait::async_trait;
-use log::info;
 
-#[derive(Clone, Copy)]
-pub struct LinuxWorker {}
+#[derive(Clone)]
+pub struct LinuxWorker {
+    judge: Judge,
+}
 
 impl LinuxWorker {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(judge: Judge) -> Self {
+        Self { judge }
     }
 }
 
 #[async_trait]
 impl PlatformWorker for LinuxWorker {
     async fn judge(&self, config: &JudgeConfig) -> Result<JudgeResult, String> {
-        info!("{}", config);
-
-        Err("not implemented.".to_string())
+        self.judge.judge(config).await
     }
 }
@@ -1,6 +1,36 @@
 #[cfg(target_os = "linux")]
+mod cgroup;
+#[cfg(unix)]
+mod checker;
+#[cfg(unix)]
+mod comparator;
+#[cfg(unix)]
+mod condition;
+#[cfg(target_os = "linux")]
+pub mod cri_executor;
+#[cfg(unix)]
+pub mod executor;
+#[cfg(unix)]
+pub mod judge;
+#[cfg(unix)]
+pub mod language;
+#[cfg(target_os = "linux")]
 pub mod linux_worker;
+#[cfg(target_os = "linux")]
+pub mod native_executor;
+#[cfg(unix)]
+pub mod plan;
+pub mod pool;
+#[cfg(unix)]
+pub mod preset;
+#[cfg(target_os = "linux")]
+mod sandbox;
+#[cfg(unix)]
+pub mod script;
 #[cfg(target_os = "windows")]
 pub mod windows_worker;
+#[cfg(unix)]
+mod workspace;
 
+#[allow(clippy::module_inception)]
 pub mod worker;
@@ -0,0 +1,112 @@
+use super::{
+    cgroup::Cgroup,
+    executor::{Environment, Execution, Executor, Limits},
+    sandbox::Sandbox,
+};
+use async_trait::async_trait;
+use futures::channel::oneshot;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+use tracing::warn;
+
+static RUN_SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs programs on the judge host itself in fresh namespaces, limited by
+/// cgroups v2 where available.
+pub struct NativeExecutor {
+    work_dir: PathBuf,
+    cgroup: Option<PathBuf>,
+}
+
+impl NativeExecutor {
+    pub fn new(work_dir: PathBuf, cgroup: Option<PathBuf>) -> Self {
+        Self { work_dir, cgroup }
+    }
+
+    /// Checks that runs can be placed below the given cgroup, returning it
+    /// only if they can.
+    pub fn prepare_cgroup(path: PathBuf) -> Option<PathBuf> {
+        match Cgroup::prepare(&path) {
+            Ok(()) => Some(path),
+            Err(err) => {
+                warn!(
+                    cgroup = %path.display(),
+                    "cgroup v2 is unavailable ({}), falling back to rlimits.",
+                    err
+                );
+                None
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Executor for NativeExecutor {
+    async fn prepare(
+        &self,
+        name: &str,
+        _image: Option<&str>,
+    ) -> Result<Box<dyn Environment>, String> {
+        let dir = self.work_dir.join(format!(
+            "{}-{}-{}",
+            name,
+            std::process::id(),
+            RUN_SEQUENCE.fetch_add(1, Ordering::SeqCst)
+        ));
+        let sandbox = Sandbox::new(
+            &dir.join("root"),
+            &dir.join("workspace"),
+            self.cgroup.as_deref(),
+        )
+        .map_err(|err| format!("failed to prepare sandbox: {}", err))?;
+
+        Ok(Box::new(NativeEnvironment { dir, sandbox }))
+    }
+}
+
+struct NativeEnvironment {
+    dir: PathBuf,
+    sandbox: Sandbox,
+}
+
+#[async_trait]
+impl Environment for NativeEnvironment {
+    fn workspace(&self) -> &Path {
+        self.sandbox.workspace()
+    }
+
+    async fn run(
+        &self,
+        args: &[String],
+        limits: &Limits,
+        stdin: Option<&Path>,
+        stdout: &Path,
+        stderr: &Path,
+    ) -> Result<Execution, String> {
+        // the sandbox blocks until the program exits, so it gets a thread of
+        // its own to let replicas run side by side
+        let sandbox = self.sandbox.clone();
+        let (args, limits) = (args.to_vec(), *limits);
+        let stdin = stdin.map(Path::to_path_buf);
+        let (stdout, stderr) = (stdout.to_path_buf(), stderr.to_path_buf());
+        let (sender, receiver) = oneshot::channel();
+        thread::spawn(move || {
+            let execution = sandbox
+                .run(&args, &limits, stdin.as_deref(), &stdout, &stderr)
+                .map_err(|err| format!("failed to run {}: {}", args.join(" "), err));
+            let _ = sender.send(execution);
+        });
+
+        receiver
+            .await
+            .map_err(|_| "sandbox thread panicked".to_string())?
+    }
+
+    async fn cleanup(self: Box<Self>) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
@@ -0,0 +1,272 @@
+use super::{
+    comparator::Comparator,
+    condition::Condition,
+    script::{self, Hook},
+};
+use crate::schema::Stage;
+use std::collections::{BTreeSet, HashMap};
+
+/// How a stage runs the program, as asked for by `Script.run`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RunMode {
+    /// On the input of the stage, `batch` or unset
+    Batch,
+    /// Talking to the interactor, `interactive`
+    Interactive,
+    /// Not at all, the stage only needs the program to compile, `none`
+    Never,
+    /// However a run script says
+    Script,
+}
+
+/// The order stages run in, derived from the DAG their `require` entries
+/// form.
+pub struct Plan {
+    order: Vec<usize>,
+    requirements: Vec<Option<(usize, Option<Condition>)>>,
+    random: bool,
+    interactive: bool,
+}
+
+impl Plan {
+    /// Resolves every `require` of the given stages, rejecting duplicate or
+    /// unknown stage names, malformed conditions and cycles.
+    pub fn new(stages: &[Stage]) -> Result<Self, String> {
+        let mut names = HashMap::new();
+        for (index, stage) in stages.iter().enumerate() {
+            if names.insert(stage.name.as_str(), index).is_some() {
+                return Err(format!("stage {} is defined twice", stage.name));
+            }
+        }
+
+        let (mut random, mut interactive) = (false, false);
+        for stage in stages {
+            random |= is_random(stage);
+            interactive |= validate(stage, None)
+                .map_err(|err| format!("stage {}: {}", stage.name, err))?
+                == RunMode::Interactive;
+            if let Some(replicas) = &stage.replicas {
+                if replicas.is_empty() {
+                    return Err(format!("stage {} has no replicas", stage.name));
+                }
+                for replica in replicas {
+                    random |= is_random(replica);
+                    interactive |= validate(replica, Some(stage))
+                        .map_err(|err| format!("replica {}: {}", replica.name, err))?
+                        == RunMode::Interactive;
+                    if replica.replicas.is_some() || replica.require.is_some() {
+                        return Err(format!(
+                            "replica {} of stage {} can neither have replicas nor require stages",
+                            replica.name, stage.name
+                        ));
+                    }
+                }
+            }
+        }
+
+        let mut requirements = Vec::new();
+        for stage in stages {
+            requirements.push(match &stage.require {
+                Some(require) => {
+                    let on = *names.get(require.on.as_str()).ok_or_else(|| {
+                        format!("stage {} requires unknown stage {}", stage.name, require.on)
+                    })?;
+                    let cond = match &require.cond {
+                        Some(cond) => Some(
+                            Condition::parse(cond)
+                                .map_err(|err| format!("stage {}: {}", stage.name, err))?,
+                        ),
+                        None => None,
+                    };
+                    Some((on, cond))
+                }
+                None => None,
+            });
+        }
+
+        // Kahn's algorithm, preferring the order the stages were given in
+        let mut dependents = vec![Vec::new(); stages.len()];
+        let mut ready = BTreeSet::new();
+        for (index, requirement) in requirements.iter().enumerate() {
+            match requirement {
+                Some((on, _)) => dependents[*on].push(index),
+                None => {
+                    ready.insert(index);
+                }
+            }
+        }
+        let mut order = Vec::new();
+        while let Some(index) = ready.iter().next().copied() {
+            ready.remove(&index);
+            order.push(index);
+            ready.extend(dependents[index].iter().copied());
+        }
+
+        if let Some(start) = (0..stages.len()).find(|index| !order.contains(index)) {
+            // every stage requires at most one other, so following the
+            // requirements of a stage that was never ready ends in a cycle
+            let mut path = vec![start];
+            while let Some((on, _)) = requirements[*path.last().unwrap()] {
+                if let Some(position) = path.iter().position(|index| *index == on) {
+                    let cycle: Vec<&str> = path[position..]
+                        .iter()
+                        .map(|index| stages[*index].name.as_str())
+                        .collect();
+                    return Err(format!(
+                        "stages {} require each other in a cycle",
+                        cycle.join(", ")
+                    ));
+                }
+                path.push(on);
+            }
+        }
+
+        Ok(Self {
+            order,
+            requirements,
+            random,
+            interactive,
+        })
+    }
+
+    pub fn order(&self) -> &[usize] {
+        &self.order
+    }
+
+    /// The stage the given one requires, with the condition its result has
+    /// to satisfy.
+    pub fn requirement(&self, index: usize) -> Option<(usize, Option<&Condition>)> {
+        self.requirements[index]
+            .as_ref()
+            .map(|(on, cond)| (*on, cond.as_ref()))
+    }
+
+    /// Whether any stage runs on a random testcase.
+    pub fn random(&self) -> bool {
+        self.random
+    }
+
+    /// Whether any stage talks to the interactor.
+    pub fn interactive(&self) -> bool {
+        self.interactive
+    }
+}
+
+pub fn is_random(stage: &Stage) -> bool {
+    stage
+        .testcase
+        .as_ref()
+        .is_some_and(|entry| entry.is_random == Some(true))
+}
+
+/// The run mode of a stage, replicas falling back to the mode of their stage.
+pub fn run_mode(stage: &Stage, parent: Option<&Stage>) -> Result<RunMode, String> {
+    let mode = |stage: &Stage| stage.script.as_ref().and_then(|script| script.run.clone());
+    match mode(stage).or_else(|| parent.and_then(mode)).as_deref() {
+        Some(source) if script::is_script(source) => Ok(RunMode::Script),
+        Some("batch") | None => Ok(RunMode::Batch),
+        Some("interactive") => Ok(RunMode::Interactive),
+        Some("none") => Ok(RunMode::Never),
+        Some(mode) => Err(format!("unknown run mode {}", mode)),
+    }
+}
+
+/// Validates the comparator and the scripts of a stage, returning its run
+/// mode.
+fn validate(stage: &Stage, parent: Option<&Stage>) -> Result<RunMode, String> {
+    Comparator::of_stage(stage, parent)?;
+    if let Some(check) = stage
+        .script
+        .as_ref()
+        .and_then(|script| script.check.as_deref())
+    {
+        if !script::is_script(check) {
+            return Err(format!("check {} is not a script", check));
+        }
+    }
+    for hook in [Hook::Run, Hook::Compare, Hook::Check].iter() {
+        if let Some(source) = hook.of_stage(stage, parent) {
+            script::validate(source)
+                .map_err(|err| format!("invalid {} script: {}", hook.name(), err))?;
+        }
+    }
+
+    run_mode(stage, parent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Stages by name and the stage each one requires.
+    fn stages(requires: &[(&str, Option<&str>)]) -> Vec<Stage> {
+        requires
+            .iter()
+            .map(|(name, on)| {
+                let require = on.map(|on| json!({ "on": on, "cond": "status == \"AC\"" }));
+                serde_json::from_value(json!({ "name": name, "grade": 100, "require": require }))
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn runs_requirements_first() {
+        let plan = Plan::new(&stages(&[
+            ("c", Some("b")),
+            ("a", None),
+            ("b", Some("a")),
+            ("d", Some("a")),
+        ]))
+        .unwrap();
+        assert_eq!(plan.order(), &[1, 2, 0, 3]);
+        assert_eq!(plan.requirement(1).map(|(on, _)| on), None);
+        let (on, cond) = plan.requirement(0).unwrap();
+        assert_eq!(on, 2);
+        assert!(cond.is_some());
+    }
+
+    #[test]
+    fn keeps_the_given_order_otherwise() {
+        let plan = Plan::new(&stages(&[("a", None), ("b", Some("a")), ("c", None)])).unwrap();
+        assert_eq!(plan.order(), &[0, 1, 2]);
+    }
+
+    #[test]
+    fn detects_cycles() {
+        let err = Plan::new(&stages(&[
+            ("d", Some("a")),
+            ("a", Some("c")),
+            ("b", Some("a")),
+            ("c", Some("b")),
+            ("e", None),
+        ]))
+        .err();
+        assert_eq!(
+            err.as_deref(),
+            Some("stages a, c, b require each other in a cycle")
+        );
+        let err = Plan::new(&stages(&[("a", Some("a"))])).err();
+        assert_eq!(
+            err.as_deref(),
+            Some("stages a require each other in a cycle")
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_and_duplicate_stages() {
+        let err = Plan::new(&stages(&[("a", Some("b"))])).err();
+        assert_eq!(err.as_deref(), Some("stage a requires unknown stage b"));
+        let err = Plan::new(&stages(&[("a", None), ("a", None)])).err();
+        assert_eq!(err.as_deref(), Some("stage a is defined twice"));
+    }
+
+    #[test]
+    fn rejects_malformed_conditions() {
+        let mut stages = stages(&[("a", None), ("b", Some("a"))]);
+        stages[1].require.as_mut().unwrap().cond = Some("status ==".to_string());
+        let err = Plan::new(&stages).err();
+        assert_eq!(err.as_deref(), Some("stage b: unexpected end of condition"));
+    }
+}
@@ -0,0 +1,209 @@
+use super::worker::{JudgeRequest, PlatformWorker, Worker, REQUEUE};
+use crate::{metrics::METRICS, queue::Queue};
+use async_std::{
+    channel::{self, Receiver, Sender},
+    task,
+};
+#[cfg(target_os = "linux")]
+use nix::{
+    sched::{sched_getaffinity, sched_setaffinity, CpuSet},
+    unistd::{gettid, Pid},
+};
+use once_cell::sync::OnceCell;
+#[cfg(target_os = "linux")]
+use std::sync::mpsc;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{error, info};
+
+/// How often stopping workers are checked for having finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A worker of the pool, running on a thread of its own.
+struct Handle {
+    id: i32,
+    /// Closed to stop the worker once it is done with its current request
+    stop: Sender<()>,
+    thread: thread::JoinHandle<()>,
+    #[cfg(target_os = "linux")]
+    tid: Pid,
+}
+
+/// The workers receiving judge requests, which can be resized at runtime.
+pub struct Pool<T: PlatformWorker + Sync + Send + Clone + 'static> {
+    receiver: Receiver<JudgeRequest>,
+    publisher: &'static OnceCell<Queue>,
+    platform_worker: T,
+    /// The cores the workers get a share of each, if they are pinned
+    cores: Option<Vec<usize>>,
+    workers: Vec<Handle>,
+    stopping: Vec<Handle>,
+    next_id: i32,
+}
+
+impl<T: PlatformWorker + Sync + Send + Clone + 'static> Pool<T> {
+    pub fn new(
+        receiver: Receiver<JudgeRequest>,
+        publisher: &'static OnceCell<Queue>,
+        platform_worker: T,
+        pin: bool,
+    ) -> Self {
+        Self {
+            receiver,
+            publisher,
+            platform_worker,
+            cores: if pin { cores() } else { None },
+            workers: Vec::new(),
+            stopping: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Starts or stops workers until there are `count` of them, letting the
+    /// stopped ones finish their current request first.
+    pub fn resize(&mut self, count: usize) {
+        while self.workers.len() < count {
+            let worker = self.spawn();
+            self.workers.push(worker);
+        }
+        while self.workers.len() > count {
+            let worker = self.workers.pop().unwrap();
+            info!(worker = worker.id, "stopping worker.");
+            worker.stop.close();
+            self.stopping.push(worker);
+        }
+        self.stopping.retain(|worker| !worker.thread.is_finished());
+        METRICS.set_workers(self.workers.len());
+        self.pin();
+    }
+
+    /// Stops every worker, waiting up to `timeout` for their current requests
+    /// to finish, and requeues the requests none of them took. Returns whether
+    /// every worker finished in time.
+    pub async fn shutdown(&mut self, timeout: Duration) -> bool {
+        self.resize(0);
+        let deadline = Instant::now() + timeout;
+        let mut finished = true;
+        while self
+            .stopping
+            .iter()
+            .any(|worker| !worker.thread.is_finished())
+        {
+            if Instant::now() >= deadline {
+                finished = false;
+                break;
+            }
+            task::sleep(POLL_INTERVAL).await;
+        }
+
+        while let Ok((channel, delivery_tag, _, _, config)) = self.receiver.try_recv() {
+            METRICS.queue(-1);
+            info!(submission = config.id, "requeueing judge request.");
+            if let Err(err) = channel.basic_nack(delivery_tag, REQUEUE).await {
+                error!(
+                    submission = config.id,
+                    "failed to requeue judge request: {}", err
+                );
+            }
+        }
+
+        finished
+    }
+
+    /// Waits for every worker to stop.
+    pub fn join(self) {
+        for worker in self.workers.into_iter().chain(self.stopping) {
+            if worker.thread.join().is_err() {
+                error!(worker = worker.id, "an error occurred in worker.");
+            }
+        }
+    }
+
+    fn spawn(&mut self) -> Handle {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (stop, stopped) = channel::bounded(1);
+        let worker = Worker::new(
+            id,
+            self.receiver.clone(),
+            stopped,
+            self.publisher,
+            self.platform_worker.clone(),
+        );
+        #[cfg(target_os = "linux")]
+        let (tid_sender, tid) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            #[cfg(target_os = "linux")]
+            let _ = tid_sender.send(gettid());
+            async_std::task::block_on(worker.worker_thread());
+        });
+
+        Handle {
+            id,
+            stop,
+            thread,
+            #[cfg(target_os = "linux")]
+            tid: tid.recv().unwrap(),
+        }
+    }
+
+    /// Pins every worker to its share of the cores, which the programs it
+    /// runs inherit.
+    fn pin(&self) {
+        #[cfg(target_os = "linux")]
+        if let Some(cores) = &self.cores {
+            let count = self.workers.len();
+            for (index, worker) in self.workers.iter().enumerate() {
+                let share = share(cores, index, count);
+                let mut set = CpuSet::new();
+                for core in share {
+                    let _ = set.set(*core);
+                }
+                match sched_setaffinity(worker.tid, &set) {
+                    Ok(()) => info!(worker = worker.id, cores = ?share, "pinned worker to cores."),
+                    Err(err) => error!(worker = worker.id, "failed to pin worker: {}", err),
+                }
+            }
+        }
+    }
+}
+
+/// The cores this process may run on.
+#[cfg(target_os = "linux")]
+fn cores() -> Option<Vec<usize>> {
+    match sched_getaffinity(Pid::from_raw(0)) {
+        Ok(set) => Some(
+            (0..CpuSet::count())
+                .filter(|core| set.is_set(*core).unwrap_or(false))
+                .collect(),
+        ),
+        Err(err) => {
+            error!("failed to get the cores to pin workers to: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cores() -> Option<Vec<usize>> {
+    error!("pinning workers to cores is only supported on linux.");
+    None
+}
+
+/// The cores of the worker at `index` out of `count`, which are disjoint
+/// from the cores of the others unless there are more workers than cores.
+#[cfg(target_os = "linux")]
+fn share(cores: &[usize], index: usize, count: usize) -> &[usize] {
+    if count > cores.len() {
+        let core = index % cores.len();
+        return &cores[core..=core];
+    }
+    let size = cores.len() / count;
+    &cores[index * size..(index + 1) * size]
+}
@@ -0,0 +1,219 @@
+use crate::schema::{Aggregate, JudgeConfig, Limits, Script, Stage};
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::Path};
+
+const BUILTIN: &str = include_str!("../../presets.toml");
+
+#[derive(Deserialize)]
+pub struct Preset {
+    pub script: Option<Script>,
+    pub limits: Option<Limits>,
+    pub interactor_limits: Option<Limits>,
+    pub aggregate: Option<Aggregate>,
+}
+
+/// The presets stages may name in `Stage.preset`.
+pub struct Presets {
+    presets: HashMap<String, Preset>,
+}
+
+impl Presets {
+    pub fn builtin() -> Self {
+        Self {
+            presets: Self::parse(BUILTIN, false).unwrap(),
+        }
+    }
+
+    /// Loads presets from a TOML file, or a JSON one if the file name ends
+    /// with `.json`, on top of the built-in ones.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        let json = path
+            .extension()
+            .is_some_and(|extension| extension == "json");
+        let presets = Self::parse(&content, json)
+            .map_err(|err| format!("invalid {}: {}", path.display(), err))?;
+
+        let mut builtin = Self::builtin();
+        builtin.presets.extend(presets);
+        Ok(builtin)
+    }
+
+    fn parse(content: &str, json: bool) -> Result<HashMap<String, Preset>, String> {
+        if json {
+            serde_json::from_str(content).map_err(|err| err.to_string())
+        } else {
+            toml::from_str(content).map_err(|err| err.to_string())
+        }
+    }
+
+    /// Copies a config with the presets of its stages and replicas filled in.
+    pub fn apply(&self, config: &JudgeConfig) -> Result<JudgeConfig, String> {
+        let mut config = config.clone();
+        for stage in &mut config.stages {
+            self.apply_stage(stage)?;
+            for replica in stage.replicas.iter_mut().flatten() {
+                self.apply_stage(replica)?;
+            }
+        }
+
+        Ok(config)
+    }
+
+    fn apply_stage(&self, stage: &mut Stage) -> Result<(), String> {
+        let preset = match &stage.preset {
+            Some(name) => self
+                .presets
+                .get(name)
+                .ok_or_else(|| format!("stage {}: unknown preset {}", stage.name, name))?,
+            None => return Ok(()),
+        };
+
+        stage.script = merge(
+            stage.script.take(),
+            preset.script.as_ref(),
+            |script, preset| Script {
+                check: script.check.or_else(|| preset.check.clone()),
+                run: script.run.or_else(|| preset.run.clone()),
+                compare: script.compare.or_else(|| preset.compare.clone()),
+            },
+        );
+        stage.limits = merge(stage.limits.take(), preset.limits.as_ref(), merge_limits);
+        stage.interactor_limits = merge(
+            stage.interactor_limits.take(),
+            preset.interactor_limits.as_ref(),
+            merge_limits,
+        );
+        stage.aggregate = stage.aggregate.or(preset.aggregate);
+
+        Ok(())
+    }
+}
+
+/// Merges a field of a stage with the one of its preset, the values set on
+/// the stage winning.
+fn merge<T: Clone>(stage: Option<T>, preset: Option<&T>, merge: fn(T, &T) -> T) -> Option<T> {
+    match (stage, preset) {
+        (Some(stage), Some(preset)) => Some(merge(stage, preset)),
+        (stage, preset) => stage.or_else(|| preset.cloned()),
+    }
+}
+
+fn merge_limits(limits: Limits, preset: &Limits) -> Limits {
+    Limits {
+        time: limits.time.or(preset.time),
+        memory: limits.memory.or(preset.memory),
+        file: limits.file.or(preset.file),
+        proc: limits.proc.or(preset.proc),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config(stage: serde_json::Value) -> JudgeConfig {
+        serde_json::from_value(json!({
+            "id": 1,
+            "version": crate::schema::VERSION,
+            "type": "programming",
+            "program": { "language": "c", "compile_args": [], "sources": [] },
+            "stages": [stage],
+            "testcases": []
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn stage_values_override_the_preset() {
+        let config = config(json!({
+            "name": "s1",
+            "grade": 10,
+            "preset": "run-interactive",
+            "script": { "check": "true" },
+            "limits": { "time": 2000, "proc": 4 },
+            "replicas": [{
+                "name": "r1",
+                "grade": 10,
+                "preset": "run-batch",
+                "script": { "compare": "tokens" },
+                "aggregate": "min"
+            }]
+        }));
+        let stage = &Presets::builtin().apply(&config).unwrap().stages[0];
+
+        let script = stage.script.as_ref().unwrap();
+        assert_eq!(script.run.as_deref(), Some("interactive"));
+        assert_eq!(script.check.as_deref(), Some("true"));
+        assert_eq!(script.compare, None);
+        let limits = stage.limits.as_ref().unwrap();
+        assert_eq!(limits.time, Some(2000));
+        assert_eq!(limits.memory, Some(268435456));
+        assert_eq!(limits.proc, Some(4));
+        assert_eq!(limits.file, None);
+        assert_eq!(stage.interactor_limits.as_ref().unwrap().time, Some(10000));
+
+        let replica = &stage.replicas.as_ref().unwrap()[0];
+        let script = replica.script.as_ref().unwrap();
+        assert_eq!(script.run.as_deref(), Some("batch"));
+        assert_eq!(script.compare.as_deref(), Some("tokens"));
+        assert_eq!(replica.limits.as_ref().unwrap().time, Some(1000));
+        assert!(matches!(replica.aggregate, Some(Aggregate::Min)));
+    }
+
+    #[test]
+    fn rejects_unknown_presets() {
+        let config = config(json!({ "name": "s1", "grade": 10, "preset": "fast" }));
+        assert_eq!(
+            Presets::builtin().apply(&config).err().unwrap(),
+            "stage s1: unknown preset fast"
+        );
+    }
+
+    #[test]
+    fn loads_presets_over_the_builtin_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("presets.toml");
+        fs::write(
+            &path,
+            r#"
+            [run-batch]
+            script = { run = "batch", compare = "float:1e-6" }
+
+            [slow]
+            limits = { time = 5000 }
+            "#,
+        )
+        .unwrap();
+        let presets = Presets::load(&path).unwrap();
+
+        let stage = |preset: &str| {
+            let config = config(json!({ "name": "s1", "grade": 10, "preset": preset }));
+            presets.apply(&config).unwrap().stages.remove(0)
+        };
+        let batch = stage("run-batch");
+        assert_eq!(batch.script.unwrap().compare.as_deref(), Some("float:1e-6"));
+        // a preset of the file replaces the built-in one of the same name
+        assert!(batch.limits.is_none());
+        assert_eq!(stage("slow").limits.unwrap().time, Some(5000));
+        assert_eq!(
+            stage("compile").script.unwrap().run.as_deref(),
+            Some("none")
+        );
+
+        let path = dir.path().join("presets.json");
+        fs::write(&path, r#"{ "slow": { "limits": { "time": 3000 } } }"#).unwrap();
+        let presets = Presets::load(&path).unwrap();
+        let config = config(json!({ "name": "s1", "grade": 10, "preset": "slow" }));
+        assert_eq!(
+            presets.apply(&config).unwrap().stages[0]
+                .limits
+                .as_ref()
+                .unwrap()
+                .time,
+            Some(3000)
+        );
+    }
+}
@@ -0,0 +1,618 @@
+use super::{
+    cgroup::Cgroup,
+    executor::{is_pipe, Exceeded, Execution, Limits},
+    workspace,
+};
+use nix::{
+    mount::{mount, MsFlags},
+    sched::{unshare, CloneFlags},
+    sys::statvfs::{statvfs, FsFlags},
+    unistd::{chdir, chroot, fork, ForkResult},
+};
+use std::{
+    ffi::{CStr, CString},
+    fs::{self, File},
+    io,
+    os::unix::{ffi::OsStrExt, fs::chown, process::CommandExt},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::atomic::{AtomicI32, AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const SANDBOX_DIR: &str = "/sandbox";
+
+const HOST_MOUNTS: &[&str] = &[
+    "/bin", "/etc", "/lib", "/lib32", "/lib64", "/libx32", "/opt", "/sbin", "/usr",
+];
+const DEVICES: &[&str] = &["/dev/null", "/dev/zero", "/dev/random", "/dev/urandom"];
+const PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+const NOBODY: u32 = 65534;
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+/// How long a run asked to stop has to be reaped before it is killed outright.
+const KILL_GRACE: Duration = Duration::from_secs(1);
+
+static CGROUP_SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+/// The namespace init the mirror forwards a stop request to.
+static INIT: AtomicI32 = AtomicI32::new(0);
+
+#[derive(Clone)]
+pub struct Sandbox {
+    root: PathBuf,
+    workspace: PathBuf,
+    cgroup: Option<PathBuf>,
+    uid: u32,
+    gid: u32,
+}
+
+impl Sandbox {
+    /// Lays out an empty root file system under `root` whose mount points are
+    /// populated inside the namespaces of every run, with `workspace` mounted
+    /// read-write at `SANDBOX_DIR`. Runs are accounted in cgroups created
+    /// below `cgroup` when given, otherwise only rlimits apply.
+    pub fn new(root: &Path, workspace: &Path, cgroup: Option<&Path>) -> io::Result<Self> {
+        let (uid, gid) = if unsafe { libc::geteuid() } == 0 {
+            (NOBODY, NOBODY)
+        } else {
+            unsafe { (libc::geteuid(), libc::getegid()) }
+        };
+
+        for dir in HOST_MOUNTS.iter().filter(|dir| Path::new(dir).is_dir()) {
+            fs::create_dir_all(root.join(&dir[1..]))?;
+        }
+        fs::create_dir_all(root.join("dev"))?;
+        for device in DEVICES {
+            File::create(root.join(&device[1..]))?;
+        }
+        for dir in &["proc", "tmp", &SANDBOX_DIR[1..]] {
+            fs::create_dir_all(root.join(dir))?;
+        }
+
+        fs::create_dir_all(workspace)?;
+        chown(workspace, Some(uid), Some(gid))?;
+
+        Ok(Self {
+            root: root.to_path_buf(),
+            workspace: workspace.to_path_buf(),
+            cgroup: cgroup.map(Path::to_path_buf),
+            uid,
+            gid,
+        })
+    }
+
+    pub fn workspace(&self) -> &Path {
+        &self.workspace
+    }
+
+    pub fn run(
+        &self,
+        args: &[String],
+        limits: &Limits,
+        stdin: Option<&Path>,
+        stdout: &Path,
+        stderr: &Path,
+    ) -> io::Result<Execution> {
+        let program = args
+            .first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command line"))?;
+
+        // only an interactive run, whose stdout is a named pipe, reads from
+        // one as well
+        let interactive = is_pipe(stdout);
+        let stdout = if interactive {
+            workspace::open_pipe(&self.workspace, stdout, true)?
+        } else {
+            workspace::create(&self.workspace, stdout)?
+        };
+        let stdin = match stdin {
+            Some(path) if interactive && is_pipe(path) => {
+                Stdio::from(workspace::open_pipe(&self.workspace, path, false)?)
+            }
+            Some(path) => Stdio::from(workspace::open(&self.workspace, path)?),
+            None => Stdio::null(),
+        };
+        let mut command = Command::new(program);
+        command
+            .args(&args[1..])
+            .env_clear()
+            .env("PATH", PATH)
+            .env("HOME", SANDBOX_DIR)
+            .stdout(Stdio::from(stdout))
+            .stdin(stdin)
+            .stderr(Stdio::from(workspace::create(&self.workspace, stderr)?));
+
+        let cgroup = match &self.cgroup {
+            Some(parent) => {
+                let cgroup = Cgroup::new(
+                    parent,
+                    &format!(
+                        "{}-{}",
+                        std::process::id(),
+                        CGROUP_SEQUENCE.fetch_add(1, Ordering::SeqCst)
+                    ),
+                )?;
+                if let Some(memory) = limits.memory {
+                    cgroup.limit_memory(memory)?;
+                }
+                cgroup.limit_pids(limits.proc)?;
+                Some(cgroup)
+            }
+            None => None,
+        };
+
+        let setup = Setup::new(self, limits, cgroup.as_ref())?;
+        unsafe {
+            command.pre_exec(move || setup.enter());
+        }
+
+        let start = Instant::now();
+        let child = command.spawn()?;
+        let pid = child.id() as libc::pid_t;
+        let wall_limit = limits.wall_time();
+        let mut exceeded = None;
+        let mut stopped: Option<Instant> = None;
+        let (status, usage) = loop {
+            // without a cgroup, a stopped run is only waited for in polls so
+            // that it can be killed outright if it does not stop in time
+            if let Some(result) = wait(pid, exceeded.is_none() || cgroup.is_none())? {
+                break result;
+            }
+            if let Some(stopped) = stopped {
+                if stopped.elapsed() > KILL_GRACE {
+                    unsafe {
+                        libc::kill(pid, libc::SIGKILL);
+                    }
+                }
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+
+            let cpu_time = match &cgroup {
+                Some(cgroup) => Some(cgroup.cpu_usage()?),
+                None => None,
+            };
+            let over_time = limits
+                .time
+                .is_some_and(|time| cpu_time.is_some_and(|cpu| cpu > time))
+                || wall_limit.is_some_and(|wall| start.elapsed() > wall);
+            if over_time {
+                exceeded = Some(Exceeded::Time);
+                match &cgroup {
+                    Some(cgroup) => cgroup.kill(pid),
+                    // the init of the namespace kills and reaps every process
+                    // in it, so that their usage reaches the spawned process
+                    None => unsafe {
+                        libc::kill(pid, libc::SIGTERM);
+                        stopped = Some(Instant::now());
+                    },
+                }
+            } else {
+                thread::sleep(POLL_INTERVAL);
+            }
+        };
+        let wall_time = start.elapsed();
+
+        let mut execution = Execution {
+            exit_code: if libc::WIFEXITED(status) {
+                Some(libc::WEXITSTATUS(status))
+            } else {
+                None
+            },
+            signal: if libc::WIFSIGNALED(status) {
+                Some(libc::WTERMSIG(status))
+            } else {
+                None
+            },
+            cpu_time: timeval(usage.ru_utime) + timeval(usage.ru_stime),
+            wall_time,
+            memory: usage.ru_maxrss as u64 * 1024,
+            exceeded,
+        };
+        if let Some(cgroup) = &cgroup {
+            execution.cpu_time = execution.cpu_time.max(cgroup.cpu_usage()?);
+            execution.memory = cgroup.memory_peak().unwrap_or(execution.memory);
+        }
+        if execution.exceeded.is_none() {
+            execution.exceeded = exceeded_limit(&execution, limits, cgroup.as_ref());
+        }
+
+        Ok(execution)
+    }
+}
+
+fn exceeded_limit(
+    execution: &Execution,
+    limits: &Limits,
+    cgroup: Option<&Cgroup>,
+) -> Option<Exceeded> {
+    let over_memory = match cgroup {
+        Some(cgroup) => cgroup.oom_killed(),
+        None => limits
+            .memory
+            .is_some_and(|memory| execution.memory > memory),
+    };
+
+    if over_memory {
+        Some(Exceeded::Memory)
+    } else if execution.signal == Some(libc::SIGXFSZ) {
+        Some(Exceeded::Output)
+    } else if execution.signal == Some(libc::SIGXCPU)
+        || limits.time.is_some_and(|time| execution.cpu_time > time)
+    {
+        Some(Exceeded::Time)
+    } else if cgroup.is_some_and(Cgroup::pids_exhausted) {
+        Some(Exceeded::Process)
+    } else {
+        None
+    }
+}
+
+struct Mount {
+    source: CString,
+    target: CString,
+    flags: MsFlags,
+}
+
+/// Everything the forked child needs, prepared up front so that nothing is
+/// allocated between `fork` and `exec`.
+struct Setup {
+    cgroup_procs: Option<CString>,
+    rlimits: Vec<(libc::__rlimit_resource_t, libc::rlim_t, libc::rlim_t)>,
+    uid: u32,
+    gid: u32,
+    uid_map: CString,
+    gid_map: CString,
+    root: CString,
+    binds: Vec<Mount>,
+    proc: CString,
+    tmp: CString,
+}
+
+impl Setup {
+    fn new(sandbox: &Sandbox, limits: &Limits, cgroup: Option<&Cgroup>) -> io::Result<Self> {
+        let mut rlimits = vec![(libc::RLIMIT_CORE, 0, 0)];
+        if let Some(file) = limits.file {
+            rlimits.push((libc::RLIMIT_FSIZE, file, file));
+        }
+        if let Some(time) = limits.time {
+            // without a cgroup the watchdog cannot see the usage of the
+            // running program, SIGXCPU then reports it instead
+            let seconds = time.as_secs() + 1;
+            rlimits.push((libc::RLIMIT_CPU, seconds, seconds + 1));
+        }
+
+        let mut binds = Vec::new();
+        for dir in HOST_MOUNTS.iter().filter(|dir| Path::new(dir).is_dir()) {
+            binds.push(Mount {
+                source: cstring(Path::new(dir))?,
+                target: cstring(&sandbox.root.join(&dir[1..]))?,
+                flags: locked_flags(Path::new(dir))? | MsFlags::MS_RDONLY,
+            });
+        }
+        for device in DEVICES {
+            binds.push(Mount {
+                source: cstring(Path::new(device))?,
+                target: cstring(&sandbox.root.join(&device[1..]))?,
+                flags: locked_flags(Path::new(device))? | MsFlags::MS_NOSUID,
+            });
+        }
+        binds.push(Mount {
+            source: cstring(&sandbox.workspace)?,
+            target: cstring(&sandbox.root.join(&SANDBOX_DIR[1..]))?,
+            flags: locked_flags(&sandbox.workspace)? | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        });
+
+        Ok(Self {
+            cgroup_procs: match cgroup {
+                Some(cgroup) => Some(cstring(&cgroup.procs())?),
+                None => None,
+            },
+            rlimits,
+            uid: sandbox.uid,
+            gid: sandbox.gid,
+            uid_map: cstring_from(format!("0 {} 1", sandbox.uid))?,
+            gid_map: cstring_from(format!("0 {} 1", sandbox.gid))?,
+            root: cstring(&sandbox.root)?,
+            binds,
+            proc: cstring(&sandbox.root.join("proc"))?,
+            tmp: cstring(&sandbox.root.join("tmp"))?,
+        })
+    }
+
+    /// Runs in the forked child: joins the cgroup of the run, applies the
+    /// rlimits, drops to the sandbox user, enters fresh user/PID/mount/
+    /// network/IPC/UTS namespaces and forks once more so the judged program
+    /// becomes the init of its own PID namespace.
+    fn enter(&self) -> io::Result<()> {
+        if let Some(procs) = &self.cgroup_procs {
+            write(procs, b"0")?;
+        }
+
+        unsafe {
+            for (resource, soft, hard) in &self.rlimits {
+                let limit = libc::rlimit {
+                    rlim_cur: *soft,
+                    rlim_max: *hard,
+                };
+                check(libc::setrlimit(*resource, &limit))?;
+            }
+
+            if libc::geteuid() != self.uid {
+                check(libc::setgroups(0, std::ptr::null()))?;
+                check(libc::setresgid(self.gid, self.gid, self.gid))?;
+                check(libc::setresuid(self.uid, self.uid, self.uid))?;
+                check(libc::prctl(libc::PR_SET_DUMPABLE, 1))?;
+            }
+        }
+
+        sys(unshare(
+            CloneFlags::CLONE_NEWUSER
+                | CloneFlags::CLONE_NEWNS
+                | CloneFlags::CLONE_NEWPID
+                | CloneFlags::CLONE_NEWNET
+                | CloneFlags::CLONE_NEWIPC
+                | CloneFlags::CLONE_NEWUTS,
+        ))?;
+        write(c_str(b"/proc/self/setgroups\0"), b"deny")?;
+        write(c_str(b"/proc/self/uid_map\0"), self.uid_map.as_bytes())?;
+        write(c_str(b"/proc/self/gid_map\0"), self.gid_map.as_bytes())?;
+
+        let mut report = [0; 2];
+        check(unsafe { libc::pipe2(report.as_mut_ptr(), libc::O_CLOEXEC) })?;
+        match sys(unsafe { fork() })? {
+            ForkResult::Parent { child } => unsafe { mirror(child.as_raw(), report[0]) },
+            ForkResult::Child => unsafe {
+                libc::close(report[0]);
+                check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+            },
+        }
+
+        let none: Option<&CStr> = None;
+        sys(mount(
+            none,
+            c_str(b"/\0"),
+            none,
+            MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+            none,
+        ))?;
+        for bind in &self.binds {
+            sys(mount(
+                Some(bind.source.as_c_str()),
+                bind.target.as_c_str(),
+                none,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                none,
+            ))?;
+            sys(mount(
+                none,
+                bind.target.as_c_str(),
+                none,
+                MsFlags::MS_REMOUNT | MsFlags::MS_BIND | bind.flags,
+                none,
+            ))?;
+        }
+        // a fresh procfs may be refused when the host masks parts of its own
+        // one, the sandbox then simply goes without
+        let _ = mount(
+            Some(c_str(b"proc\0")),
+            self.proc.as_c_str(),
+            Some(c_str(b"proc\0")),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            none,
+        );
+        sys(mount(
+            Some(c_str(b"tmpfs\0")),
+            self.tmp.as_c_str(),
+            Some(c_str(b"tmpfs\0")),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some(c_str(b"size=64m\0")),
+        ))?;
+
+        sys(chroot(self.root.as_c_str()))?;
+        sys(chdir(c_str(b"/sandbox\0")))?;
+
+        // the init of a PID namespace is immune to signals it has no handler
+        // for, which would swallow SIGXCPU and SIGXFSZ, so the judged program
+        // runs as its child instead
+        match sys(unsafe { fork() })? {
+            ForkResult::Parent { child } => unsafe { reap(child.as_raw(), report[1]) },
+            ForkResult::Child => unsafe {
+                check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+            },
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs as the init of the PID namespace: reaps every orphan until the judged
+/// program exits and reports its wait status to the supervisor outside.
+/// Whatever is still running then is killed and reaped too, so that the
+/// usage of the whole tree counts, and so is everything once the supervisor
+/// asks the run to stop.
+unsafe fn reap(program: libc::pid_t, report: libc::c_int) -> ! {
+    close_fds(report);
+    libc::signal(
+        libc::SIGTERM,
+        kill_namespace as *const () as libc::sighandler_t,
+    );
+
+    let mut status = 0;
+    loop {
+        let pid = libc::waitpid(-1, &mut status, 0);
+        if pid == program {
+            break;
+        }
+        if pid < 0 && *libc::__errno_location() != libc::EINTR {
+            libc::_exit(127);
+        }
+    }
+
+    libc::kill(-1, libc::SIGKILL);
+    let mut other = 0;
+    while libc::waitpid(-1, &mut other, 0) >= 0 || *libc::__errno_location() == libc::EINTR {}
+
+    libc::write(
+        report,
+        &status as *const libc::c_int as *const libc::c_void,
+        std::mem::size_of::<libc::c_int>(),
+    );
+    libc::_exit(0)
+}
+
+/// Waits for the namespace init and takes on the fate of the judged program,
+/// so the worker sees its exit status and resource usage on the process it
+/// spawned.
+unsafe fn mirror(init: libc::pid_t, report: libc::c_int) -> ! {
+    close_fds(report);
+    INIT.store(init, Ordering::SeqCst);
+    libc::signal(libc::SIGTERM, stop_init as *const () as libc::sighandler_t);
+
+    let mut reported = 0;
+    let size = std::mem::size_of::<libc::c_int>() as isize;
+    let mut read;
+    loop {
+        read = libc::read(
+            report,
+            &mut reported as *mut libc::c_int as *mut libc::c_void,
+            size as usize,
+        );
+        if read >= 0 || *libc::__errno_location() != libc::EINTR {
+            break;
+        }
+    }
+
+    let mut status = 0;
+    while libc::waitpid(init, &mut status, 0) < 0 {
+        if *libc::__errno_location() != libc::EINTR {
+            libc::_exit(127);
+        }
+    }
+    if read == size {
+        status = reported;
+    }
+
+    if libc::WIFSIGNALED(status) {
+        let signal = libc::WTERMSIG(status);
+        libc::signal(signal, libc::SIG_DFL);
+        libc::kill(libc::getpid(), signal);
+        libc::_exit(128 + signal);
+    }
+    libc::_exit(libc::WEXITSTATUS(status))
+}
+
+/// Forwards a stop request of the supervisor to the namespace init, which
+/// the supervisor cannot see.
+extern "C" fn stop_init(_: libc::c_int) {
+    let init = INIT.load(Ordering::SeqCst);
+    if init > 0 {
+        unsafe {
+            libc::kill(init, libc::SIGTERM);
+        }
+    }
+}
+
+extern "C" fn kill_namespace(_: libc::c_int) {
+    unsafe {
+        libc::kill(-1, libc::SIGKILL);
+    }
+}
+
+unsafe fn close_fds(keep: libc::c_int) {
+    for &(first, last) in &[(3, keep - 1), (keep + 1, libc::c_int::MAX)] {
+        if first <= last
+            && libc::syscall(
+                libc::SYS_close_range,
+                first as libc::c_uint,
+                last as libc::c_uint,
+                0,
+            ) != 0
+        {
+            for fd in first..=last.min(1023) {
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+fn wait(pid: libc::pid_t, poll: bool) -> io::Result<Option<(libc::c_int, libc::rusage)>> {
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let options = if poll { libc::WNOHANG } else { 0 };
+    loop {
+        match unsafe { libc::wait4(pid, &mut status, options, &mut usage) } {
+            0 => return Ok(None),
+            ret if ret > 0 => return Ok(Some((status, usage))),
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+fn locked_flags(path: &Path) -> io::Result<MsFlags> {
+    let flags = sys(statvfs(path))?.flags();
+    let mut result = MsFlags::empty();
+    for (fs_flag, ms_flag) in &[
+        (FsFlags::ST_RDONLY, MsFlags::MS_RDONLY),
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if flags.contains(*fs_flag) {
+            result |= *ms_flag;
+        }
+    }
+    Ok(result)
+}
+
+fn write(path: &CStr, data: &[u8]) -> io::Result<()> {
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        check(fd)?;
+        let written = libc::write(fd, data.as_ptr() as *const libc::c_void, data.len());
+        libc::close(fd);
+        if written != data.len() as isize {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn sys<T>(result: nix::Result<T>) -> io::Result<T> {
+    result.map_err(|err| match err.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => io::Error::other(err.to_string()),
+    })
+}
+
+fn c_str(bytes: &'static [u8]) -> &'static CStr {
+    CStr::from_bytes_with_nul(bytes).unwrap()
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    cstring_from(path.as_os_str().as_bytes().to_vec())
+}
+
+fn cstring_from<T: Into<Vec<u8>>>(bytes: T) -> io::Result<CString> {
+    CString::new(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+fn timeval(time: libc::timeval) -> Duration {
+    Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+}
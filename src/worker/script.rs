@@ -0,0 +1,609 @@
+use super::{checker::Outcome, comparator::Comparator, executor::Execution, workspace};
+use crate::schema::{Stage, Verdict};
+use futures::channel::{mpsc, oneshot};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope};
+use std::{
+    io::Write,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+const MAX_OPERATIONS: u64 = 100_000_000;
+const MAX_STRING_SIZE: usize = 64 << 20;
+const MAX_COLLECTION_SIZE: usize = 1 << 20;
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Where in a stage a script of `Script` is evaluated.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Hook {
+    /// Runs in place of the program, `Script.run`
+    Run,
+    /// Judges the output of a batch run, `Script.compare`
+    Compare,
+    /// Has the last word on the result of the stage, `Script.check`
+    Check,
+}
+
+/// The files a script works on and what it is judging.
+pub struct Context {
+    /// The workspace of the program, which relative paths are resolved in
+    pub workspace: PathBuf,
+    pub input: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub answer: Option<PathBuf>,
+    /// The result of the stage so far, given to check scripts
+    pub result: Option<Map>,
+    pub grade: i32,
+}
+
+/// A script asking for the program to be run with extra arguments.
+pub struct RunRequest {
+    pub args: Vec<String>,
+    pub stdin: Option<PathBuf>,
+    pub reply: oneshot::Sender<Result<Map, String>>,
+}
+
+/// What a script decided, any of which may be left unset.
+#[derive(Default)]
+pub struct Report {
+    status: Option<Verdict>,
+    score: Option<i32>,
+    message: Option<String>,
+    /// The value of a script that evaluated to a bool
+    passed: Option<bool>,
+}
+
+/// A script being evaluated on a thread of its own, sending the runs it asks
+/// for until it is done.
+pub struct Session {
+    pub requests: mpsc::UnboundedReceiver<RunRequest>,
+    done: oneshot::Receiver<Result<Report, String>>,
+}
+
+impl Session {
+    pub async fn finish(self) -> Result<Report, String> {
+        self.done
+            .await
+            .map_err(|_| "the script panicked".to_string())?
+    }
+}
+
+impl Hook {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Hook::Run => "run",
+            Hook::Compare => "compare",
+            Hook::Check => "check",
+        }
+    }
+
+    /// The script of a stage for this hook, replicas falling back to the one
+    /// of their stage. Single words such as `interactive` or `tokens` are
+    /// names rather than scripts.
+    pub fn of_stage<'a>(&self, stage: &'a Stage, parent: Option<&'a Stage>) -> Option<&'a str> {
+        let source = |stage: &'a Stage| {
+            let script = stage.script.as_ref()?;
+            match self {
+                Hook::Run => script.run.as_deref(),
+                Hook::Compare => script.compare.as_deref(),
+                Hook::Check => script.check.as_deref(),
+            }
+        };
+        source(stage)
+            .or_else(|| parent.and_then(source))
+            .filter(|source| is_script(source))
+    }
+}
+
+impl Report {
+    /// Applies what the script decided on top of an outcome, a verdict
+    /// without a score scoring the grade when accepted and zero otherwise.
+    pub fn apply(self, outcome: Outcome, grade: i32) -> Outcome {
+        let status = self.status.or_else(|| {
+            self.passed.map(|passed| {
+                if passed {
+                    Verdict::Accepted
+                } else {
+                    Verdict::WrongAnswer
+                }
+            })
+        });
+        let score = match (status, self.score) {
+            (_, Some(score)) => score.clamp(0, grade.max(0)),
+            (Some(Verdict::Accepted), None) => grade,
+            (Some(_), None) => 0,
+            (None, None) => outcome.score,
+        };
+
+        Outcome {
+            status: status.unwrap_or(outcome.status),
+            score,
+            message: self.message.or(outcome.message),
+        }
+    }
+
+    pub fn decided(&self) -> bool {
+        self.status.is_some() || self.passed.is_some()
+    }
+}
+
+pub fn is_script(value: &str) -> bool {
+    !value
+        .chars()
+        .all(|c| c.is_alphanumeric() || "-_:.+".contains(c))
+}
+
+/// Parses a script without evaluating it.
+pub fn validate(source: &str) -> Result<(), String> {
+    engine()
+        .compile(source)
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+/// Describes a stage result to a check script.
+pub fn result(outcome: &Outcome, execution: &Execution) -> Map {
+    let mut map = Map::new();
+    map.insert("status".into(), outcome.status.to_string().into());
+    map.insert("score".into(), (outcome.score as i64).into());
+    map.insert(
+        "message".into(),
+        outcome.message.clone().map_or(Dynamic::UNIT, Dynamic::from),
+    );
+    describe(&mut map, execution);
+    map
+}
+
+/// Describes a run of the program to a script.
+pub fn run(execution: &Execution, status: Verdict, stdout: &Path, stderr: &Path) -> Map {
+    let mut map = Map::new();
+    map.insert("status".into(), status.to_string().into());
+    map.insert(
+        "stdout".into(),
+        stdout.to_string_lossy().into_owned().into(),
+    );
+    map.insert(
+        "stderr".into(),
+        stderr.to_string_lossy().into_owned().into(),
+    );
+    describe(&mut map, execution);
+    map
+}
+
+fn describe(map: &mut Map, execution: &Execution) {
+    let int = |value: Option<i32>| value.map_or(Dynamic::UNIT, |value| (value as i64).into());
+    map.insert(
+        "time".into(),
+        (execution.cpu_time.as_millis() as i64).into(),
+    );
+    map.insert(
+        "wall_time".into(),
+        (execution.wall_time.as_millis() as i64).into(),
+    );
+    map.insert("memory".into(), (execution.memory as i64).into());
+    map.insert("exit_code".into(), int(execution.exit_code));
+    map.insert("signal".into(), int(execution.signal));
+}
+
+/// Starts evaluating a script. Besides the usual Rhai, it can call
+///
+/// - `run(args)` and `run(args, stdin)` to run the program with extra
+///   arguments, on the input of the stage unless given another file, which
+///   returns a map of `status`, `time`, `wall_time`, `memory`, `exit_code`,
+///   `signal` and the `stdout` and `stderr` files;
+/// - `read(file)`, `write(file, content)` and `exists(file)` on the files of
+///   the stage and the workspace of the program;
+/// - `compare(answer, output)` and `compare(answer, output, comparator)` to
+///   compare two files with a built-in comparator, which returns a map of
+///   `status` and `mismatch`;
+/// - `verdict(status)`, `score(score)` and `message(message)` to decide the
+///   result of the stage, or evaluate to a bool to accept or reject it.
+///
+/// The files of the stage are in `input`, `output` and `answer`, unit when
+/// missing, the grade of the stage in `grade` and, for check scripts, the
+/// result so far in `result`.
+pub fn start(hook: Hook, source: &str, context: Context) -> Session {
+    let (sender, requests) = mpsc::unbounded();
+    let (reply, done) = oneshot::channel();
+    let source = source.to_string();
+    thread::spawn(move || {
+        let _ = reply.send(
+            evaluate(&source, context, sender)
+                .map_err(|err| format!("the {} script failed: {}", hook.name(), err)),
+        );
+    });
+
+    Session { requests, done }
+}
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.set_max_string_size(MAX_STRING_SIZE);
+    engine.set_max_array_size(MAX_COLLECTION_SIZE);
+    engine.set_max_map_size(MAX_COLLECTION_SIZE);
+    engine.on_print(|text| debug!(text, "script printed."));
+    engine.on_debug(|text, _, position| debug!(%position, text, "script printed."));
+    engine
+}
+
+fn evaluate(
+    source: &str,
+    context: Context,
+    requests: mpsc::UnboundedSender<RunRequest>,
+) -> Result<Report, String> {
+    let mut engine = engine();
+    let started = Instant::now();
+    engine.on_progress(move |_| {
+        if started.elapsed() > TIMEOUT {
+            Some("timed out".into())
+        } else {
+            None
+        }
+    });
+
+    let report = Arc::new(Mutex::new(Report::default()));
+    let files = Arc::new(Files {
+        workspace: context.workspace.clone(),
+        stage: [&context.input, &context.output, &context.answer]
+            .iter()
+            .filter_map(|path| path.as_ref().cloned())
+            .collect(),
+    });
+
+    {
+        let report = report.clone();
+        engine.register_fn(
+            "verdict",
+            move |status: &str| -> Result<(), Box<EvalAltResult>> {
+                let status = serde_json::from_value(status.into())
+                    .map_err(|_| format!("unknown verdict {}", status))?;
+                report.lock().unwrap().status = Some(status);
+                Ok(())
+            },
+        );
+    }
+    {
+        let report = report.clone();
+        engine.register_fn("score", move |score: i64| {
+            report.lock().unwrap().score = Some(score.clamp(0, i32::MAX as i64) as i32);
+        });
+    }
+    {
+        let report = report.clone();
+        engine.register_fn("message", move |message: &str| {
+            report.lock().unwrap().message = Some(message.to_string());
+        });
+    }
+    {
+        let files = files.clone();
+        engine.register_fn(
+            "read",
+            move |file: &str| -> Result<String, Box<EvalAltResult>> {
+                let path = files.resolve(file)?;
+                Ok(workspace::read_to_string(&files.workspace, &path)
+                    .map_err(|err| format!("failed to read {}: {}", file, err))?)
+            },
+        );
+    }
+    {
+        let files = files.clone();
+        engine.register_fn(
+            "write",
+            move |file: &str, content: &str| -> Result<(), Box<EvalAltResult>> {
+                let path = files.resolve(file)?;
+                Ok(workspace::create(&files.workspace, &path)
+                    .and_then(|mut target| target.write_all(content.as_bytes()))
+                    .map_err(|err| format!("failed to write {}: {}", file, err))?)
+            },
+        );
+    }
+    {
+        let files = files.clone();
+        engine.register_fn(
+            "exists",
+            move |file: &str| -> Result<bool, Box<EvalAltResult>> {
+                let path = files.resolve(file)?;
+                Ok(workspace::exists(&files.workspace, &path)
+                    .map_err(|err| format!("failed to look up {}: {}", file, err))?)
+            },
+        );
+    }
+    {
+        let compare = |files: &Files, answer: &str, output: &str, comparator: &str| {
+            let comparison = Comparator::parse(comparator)?.compare(
+                &files.workspace,
+                &files.resolve(answer)?,
+                &files.resolve(output)?,
+            )?;
+            let mut map = Map::new();
+            map.insert("status".into(), comparison.status.to_string().into());
+            map.insert(
+                "mismatch".into(),
+                comparison.mismatch.map_or(Dynamic::UNIT, Dynamic::from),
+            );
+            Ok::<_, String>(map)
+        };
+        let lines = files.clone();
+        engine.register_fn(
+            "compare",
+            move |answer: &str, output: &str| -> Result<Map, Box<EvalAltResult>> {
+                Ok(compare(&lines, answer, output, "lines")?)
+            },
+        );
+        let files = files.clone();
+        engine.register_fn(
+            "compare",
+            move |answer: &str,
+                  output: &str,
+                  comparator: &str|
+                  -> Result<Map, Box<EvalAltResult>> {
+                Ok(compare(&files, answer, output, comparator)?)
+            },
+        );
+    }
+    {
+        let run = move |files: &Files, args: Array, stdin: Option<&str>| {
+            let args = args
+                .into_iter()
+                .map(|arg| arg.to_string())
+                .collect::<Vec<_>>();
+            let stdin = stdin.map(|stdin| files.resolve(stdin)).transpose()?;
+            let (reply, result) = oneshot::channel();
+            requests
+                .unbounded_send(RunRequest { args, stdin, reply })
+                .map_err(|_| "the stage is gone".to_string())?;
+            futures::executor::block_on(result).map_err(|_| "the run was dropped".to_string())?
+        };
+        let run = Arc::new(run);
+        let (default_run, input) = (run.clone(), context.input.clone());
+        let default_files = files.clone();
+        engine.register_fn(
+            "run",
+            move |args: Array| -> Result<Map, Box<EvalAltResult>> {
+                let input = input.as_ref().map(|input| input.to_string_lossy());
+                Ok((*default_run)(&default_files, args, input.as_deref())?)
+            },
+        );
+        engine.register_fn(
+            "run",
+            move |args: Array, stdin: &str| -> Result<Map, Box<EvalAltResult>> {
+                Ok((*run)(&files, args, Some(stdin))?)
+            },
+        );
+    }
+
+    let path = |path: &Option<PathBuf>| {
+        path.as_ref().map_or(Dynamic::UNIT, |path| {
+            path.to_string_lossy().into_owned().into()
+        })
+    };
+    let mut scope = Scope::new();
+    scope.push_constant("input", path(&context.input));
+    scope.push_constant("output", path(&context.output));
+    scope.push_constant("answer", path(&context.answer));
+    scope.push_constant("grade", context.grade as i64);
+    if let Some(result) = context.result {
+        scope.push_constant("result", result);
+    }
+
+    let value = engine
+        .eval_with_scope::<Dynamic>(&mut scope, source)
+        .map_err(|err| err.to_string())?;
+    // the closures registered on the engine hold on to the report too
+    drop(engine);
+
+    let mut report = Arc::try_unwrap(report)
+        .ok()
+        .and_then(|report| report.into_inner().ok())
+        .unwrap_or_default();
+    report.passed = value.as_bool().ok();
+    Ok(report)
+}
+
+/// The files a script may touch: those of the stage and any in the
+/// workspace of the program.
+struct Files {
+    workspace: PathBuf,
+    stage: Vec<PathBuf>,
+}
+
+impl Files {
+    fn resolve(&self, file: &str) -> Result<PathBuf, String> {
+        let path = Path::new(file);
+        if self.stage.iter().any(|stage| stage == path) {
+            return Ok(path.to_path_buf());
+        }
+        if path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            Ok(self.workspace.join(path))
+        } else {
+            Err(format!("{} is outside of the workspace", file))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(workspace: &Path) -> Context {
+        Context {
+            workspace: workspace.to_path_buf(),
+            input: Some(PathBuf::from("/data/1.in")),
+            output: Some(workspace.join("stdout")),
+            answer: Some(PathBuf::from("/data/1.out")),
+            result: None,
+            grade: 10,
+        }
+    }
+
+    /// Evaluates a script that does not ask for runs.
+    fn report(source: &str) -> Report {
+        let dir = tempfile::tempdir().unwrap();
+        let (requests, _) = mpsc::unbounded();
+        evaluate(source, context(dir.path()), requests).unwrap()
+    }
+
+    fn accepted() -> Outcome {
+        Outcome {
+            status: Verdict::Accepted,
+            score: 10,
+            message: Some("ok".to_string()),
+        }
+    }
+
+    fn assert_outcome(outcome: Outcome, status: Verdict, score: i32, message: Option<&str>) {
+        assert_eq!(outcome.status, status);
+        assert_eq!(outcome.score, score);
+        assert_eq!(outcome.message.as_deref(), message);
+    }
+
+    #[test]
+    fn tells_names_from_scripts() {
+        for name in ["interactive", "batch", "tokens", "float:1e-6", "lines"].iter() {
+            assert!(!is_script(name), "{}", name);
+        }
+        for source in [
+            "verdict(\"AC\")",
+            "score < 50",
+            "true;",
+            "read(output) == \"\"",
+        ]
+        .iter()
+        {
+            assert!(is_script(source), "{}", source);
+        }
+    }
+
+    #[test]
+    fn resolves_files_in_the_workspace() {
+        let files = Files {
+            workspace: PathBuf::from("/workspace"),
+            stage: vec![PathBuf::from("/data/1.in")],
+        };
+        assert_eq!(
+            files.resolve("/data/1.in").unwrap(),
+            Path::new("/data/1.in")
+        );
+        assert_eq!(
+            files.resolve("./out/a.txt").unwrap(),
+            Path::new("/workspace/out/a.txt")
+        );
+        for file in ["../secret", "out/../../secret", "/etc/passwd", "/data/2.in"].iter() {
+            assert_eq!(
+                files.resolve(file).unwrap_err(),
+                format!("{} is outside of the workspace", file)
+            );
+        }
+    }
+
+    #[test]
+    fn decides_the_stage_result() {
+        let outcome = report(r#"verdict("WA"); score(3); message("close");"#).apply(accepted(), 10);
+        assert_outcome(outcome, Verdict::WrongAnswer, 3, Some("close"));
+
+        let outcome = report(r#"verdict("PE");"#).apply(accepted(), 10);
+        assert_outcome(outcome, Verdict::PresentationError, 0, Some("ok"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let (requests, _) = mpsc::unbounded();
+        let err = evaluate(r#"verdict("OK")"#, context(dir.path()), requests)
+            .err()
+            .unwrap();
+        assert!(err.contains("unknown verdict OK"), "{}", err);
+    }
+
+    #[test]
+    fn accepts_or_rejects_on_bools() {
+        let report_true = report("grade == 10");
+        assert!(report_true.decided());
+        assert_outcome(
+            report_true.apply(
+                Outcome {
+                    score: 4,
+                    ..accepted()
+                },
+                10,
+            ),
+            Verdict::Accepted,
+            10,
+            Some("ok"),
+        );
+
+        assert_outcome(
+            report("input == ()").apply(accepted(), 10),
+            Verdict::WrongAnswer,
+            0,
+            Some("ok"),
+        );
+
+        // a verdict wins over the value of the script
+        assert_outcome(
+            report(r#"verdict("RE"); true"#).apply(accepted(), 10),
+            Verdict::RuntimeError,
+            0,
+            Some("ok"),
+        );
+
+        let undecided = report("let x = 1; x + 1");
+        assert!(!undecided.decided());
+        assert_outcome(
+            undecided.apply(accepted(), 10),
+            Verdict::Accepted,
+            10,
+            Some("ok"),
+        );
+    }
+
+    #[test]
+    fn applies_scores_within_the_grade() {
+        let report = |status, score| Report {
+            status,
+            score,
+            message: None,
+            passed: None,
+        };
+        assert_outcome(
+            report(None, Some(15)).apply(accepted(), 10),
+            Verdict::Accepted,
+            10,
+            Some("ok"),
+        );
+        assert_outcome(
+            report(Some(Verdict::Accepted), None).apply(
+                Outcome {
+                    score: 2,
+                    ..accepted()
+                },
+                10,
+            ),
+            Verdict::Accepted,
+            10,
+            Some("ok"),
+        );
+        assert_outcome(
+            report(Some(Verdict::TimeLimitExceeded), Some(5)).apply(accepted(), 10),
+            Verdict::TimeLimitExceeded,
+            5,
+            Some("ok"),
+        );
+        assert_outcome(
+            report(None, None).apply(
+                Outcome {
+                    score: 7,
+                    ..accepted()
+                },
+                10,
+            ),
+            Verdict::Accepted,
+            7,
+            Some("ok"),
+        );
+    }
+}
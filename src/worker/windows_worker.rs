@@ -1,7 +1,7 @@
 use super::worker::PlatformWorker;
 use crate::{schema::JudgeConfig, JudgeResult};
 use async_trait::async_trait;
-use log::info;
+use tracing::info;
 
 #[derive(Clone, Copy)]
 pub struct WindowsWorker {}
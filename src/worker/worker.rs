@@ -1,116 +1,317 @@
-use crate::schema::{JudgeConfig, JudgeResult};
+use crate::{
+    metrics::METRICS,
+    queue::{Queue, QueuePublisher},
+    schema::{JudgeConfig, JudgeResult},
+    validate::{self, Problem, Rejection},
+};
+use async_std::channel::{Receiver, Sender};
 use async_trait::async_trait;
-use concurrent_queue::ConcurrentQueue;
+use futures::FutureExt;
 use lapin::{
-    message::DeliveryResult,
+    message::{Delivery, DeliveryResult},
     options::{BasicAckOptions, BasicNackOptions, BasicRejectOptions},
     types::LongLongUInt,
-    Channel, ConsumerDelegate,
+    BasicProperties, Channel, ConsumerDelegate,
 };
-use log::{error, info};
 use once_cell::sync::OnceCell;
-use std_semaphore::Semaphore;
+use std::time::Instant;
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
+
+/// Hands a delivery back to the broker to be delivered again, which nacking
+/// does not by default.
+pub const REQUEUE: BasicNackOptions = BasicNackOptions {
+    multiple: false,
+    requeue: true,
+};
+
+/// A delivered judge request waiting for a worker.
+pub type JudgeRequest = (
+    /* channel */ Channel,
+    /* delivery tag */ LongLongUInt,
+    /* properties */ BasicProperties,
+    /* payload */ Vec<u8>,
+    /* judge config */ JudgeConfig,
+);
+
+/// Consumes judge requests and hands them to the workers through a bounded
+/// channel, so the broker holds back further requests while every worker is
+/// busy.
+#[derive(Clone)]
+pub struct Dispatcher {
+    sender: Sender<JudgeRequest>,
+    publisher: &'static OnceCell<Queue>,
+}
+
+impl Dispatcher {
+    pub fn new(sender: Sender<JudgeRequest>, publisher: &'static OnceCell<Queue>) -> Self {
+        Self { sender, publisher }
+    }
+
+    async fn dispatch(&self, channel: Channel, delivery: Delivery) {
+        let config = std::str::from_utf8(&delivery.data)
+            .map_err(|err| Rejection::Malformed(Problem::new("", err.to_string())))
+            .and_then(validate::parse_request);
+        match config {
+            Ok(config) => {
+                info!(submission = config.id, "accepted judge request.");
+                METRICS.queue(1);
+                // the workers only stop receiving once the process exits
+                let _ = self
+                    .sender
+                    .send((
+                        channel,
+                        delivery.delivery_tag,
+                        delivery.properties,
+                        delivery.data,
+                        config,
+                    ))
+                    .await;
+            }
+            Err(Rejection::Unsupported(id, message)) => {
+                error!(submission = id, "rejected judge request: {}.", message);
+                let result = JudgeResult::system_error(id, message);
+                let publisher = self.publisher.get().unwrap();
+                if let Err(err) = publish(publisher, &delivery.properties, &result).await {
+                    error!(submission = id, "failed to publish result: {}", err);
+                    log_unsettled(channel.basic_nack(delivery.delivery_tag, REQUEUE).await);
+                    return;
+                }
+                log_unsettled(
+                    channel
+                        .basic_ack(delivery.delivery_tag, BasicAckOptions::default())
+                        .await,
+                );
+            }
+            Err(Rejection::Malformed(problem)) => {
+                error!("malformed judge request: {}.", problem);
+                let error = problem.to_string();
+                let dead_letter = self.publisher.get().unwrap().dead_letter(
+                    &delivery.data,
+                    &delivery.properties,
+                    &error,
+                );
+                if let Err(err) = dead_letter.await {
+                    error!("failed to dead-letter malformed judge request: {}", err);
+                    log_unsettled(
+                        channel
+                            .basic_reject(delivery.delivery_tag, BasicRejectOptions::default())
+                            .await,
+                    );
+                    return;
+                }
+                log_unsettled(
+                    channel
+                        .basic_ack(delivery.delivery_tag, BasicAckOptions::default())
+                        .await,
+                );
+            }
+        }
+    }
+}
+
+impl ConsumerDelegate for Dispatcher {
+    fn on_new_delivery(
+        &self,
+        delivery: DeliveryResult,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> {
+        match delivery {
+            Ok(Some((channel, delivery))) => {
+                info!("received judge request.");
+                let dispatcher = self.clone();
+                Box::pin(async move { dispatcher.dispatch(channel, delivery).await })
+            }
+            // the consumer was cancelled, on resizing the pool or shutting down
+            Ok(None) => {
+                info!("consumer cancelled.");
+                Box::pin(async {})
+            }
+            Err(err) => {
+                error!("failed to consume message: {}", err);
+                Box::pin(async {})
+            }
+        }
+    }
+}
 
-#[derive(Copy, Clone)]
-pub struct Worker<T: PlatformWorker + Sync + Send + Copy + Clone> {
+#[derive(Clone)]
+pub struct Worker<T: PlatformWorker + Sync + Send + Clone> {
     pub id: i32,
-    queue: &'static OnceCell<
-        ConcurrentQueue<(
-            /* channel */ Channel,
-            /* delivery tag */ LongLongUInt,
-            /* judge config */ JudgeConfig,
-        )>,
-    >,
-    semaphore: &'static OnceCell<Semaphore>,
+    receiver: Receiver<JudgeRequest>,
+    stop: Receiver<()>,
+    publisher: &'static OnceCell<Queue>,
     platform_worker: T,
 }
 
-impl<T: PlatformWorker + Sync + Send + Copy + Clone> Worker<T> {
+impl<T: PlatformWorker + Sync + Send + Clone> Worker<T> {
     pub fn new(
         id: i32,
-        queue: &'static OnceCell<
-            ConcurrentQueue<(
-                /* channel */ Channel,
-                /* delivery tag */ LongLongUInt,
-                /* judge config */ JudgeConfig,
-            )>,
-        >,
-        semaphore: &'static OnceCell<Semaphore>,
+        receiver: Receiver<JudgeRequest>,
+        stop: Receiver<()>,
+        publisher: &'static OnceCell<Queue>,
         platform_worker: T,
     ) -> Self {
         Self {
             id,
-            queue,
-            semaphore,
+            receiver,
+            stop,
+            publisher,
             platform_worker,
         }
     }
 
     pub async fn worker_thread(&self) {
-        info!("worker {} started.", self.id);
-        let queue = self.queue.get().unwrap();
+        info!(worker = self.id, "worker started.");
         loop {
-            self.semaphore.get().unwrap().acquire();
-            while !queue.is_empty() {
-                let item = queue.pop();
-                if let Ok(result) = item {
-                    let (channel, delivery_tag, config) = result;
-                    match self.platform_worker.judge(&config).await {
-                        Ok(result) => {
-                            info!("{}", result);
-                            channel
-                                .basic_ack(delivery_tag, BasicAckOptions::default())
-                                .await
-                                .unwrap();
-                        }
-                        Err(err) => {
-                            error!("worker {}: an error occurred while processing judge request #{}: {}", self.id, config.id, err);
-                            channel
-                                .basic_nack(delivery_tag, BasicNackOptions::default())
-                                .await
-                                .unwrap();
-                        }
-                    };
-                }
-            }
+            // a stopped worker takes no further requests
+            let request = futures::select_biased! {
+                _ = self.stop.recv().fuse() => break,
+                request = self.receiver.recv().fuse() => request,
+            };
+            let (channel, delivery_tag, properties, payload, config) = match request {
+                Ok(request) => request,
+                Err(_) => break,
+            };
+            METRICS.queue(-1);
+            // every submission is a trace of its own
+            let span = info_span!(
+                parent: None,
+                "submission",
+                submission = config.id,
+                language = %config.program.language,
+                worker = self.id,
+                verdict = field::Empty,
+                error = field::Empty,
+                otel.status_code = field::Empty,
+            );
+            self.handle(channel, delivery_tag, properties, payload, config)
+                .instrument(span)
+                .await;
         }
+        info!(worker = self.id, "worker stopped.");
     }
-}
 
-impl<T: PlatformWorker + Sync + Send + Copy + Clone> ConsumerDelegate for Worker<T> {
-    fn on_new_delivery(
+    /// Judges a request, then publishes its result or has it retried.
+    async fn handle(
         &self,
-        delivery: DeliveryResult,
-    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> {
-        info!("worker {}: received judge request.", self.id);
-        if let Ok(delivery) = delivery {
-            if let Some((channel, delivery)) = delivery {
-                if let Ok(s) = std::str::from_utf8(&delivery.data) {
-                    if let Ok(config) = serde_json::from_str::<JudgeConfig>(s) {
-                        info!("worker {}: accepted judge request #{}.", self.id, config.id);
-                        let _ = self.queue.get().unwrap().push((
-                            channel,
-                            delivery.delivery_tag,
-                            config,
-                        ));
-                        self.semaphore.get().unwrap().release();
-                        return Box::pin(async {});
+        channel: Channel,
+        delivery_tag: LongLongUInt,
+        properties: BasicProperties,
+        payload: Vec<u8>,
+        config: JudgeConfig,
+    ) {
+        if !channel.status().connected() {
+            warn!("skipped judge request since its channel was lost, the broker redelivers it.");
+            return;
+        }
+        METRICS.busy(1);
+        let started = Instant::now();
+        let judged = self.platform_worker.judge(&config).await;
+        let verdict = match &judged {
+            Ok(result) => result.status.to_string(),
+            Err(_) => "error".to_string(),
+        };
+        METRICS.judged(&verdict, &config.program.language, started.elapsed());
+        METRICS.busy(-1);
+        let span = Span::current();
+        span.record("verdict", verdict.as_str());
+        // the request can no longer be settled once its channel is gone, and
+        // the broker redelivers it to be judged again, so a result published
+        // now would be a duplicate
+        if !channel.status().connected() {
+            warn!("dropped result since the channel of judge request was lost, the broker redelivers it.");
+            return;
+        }
+        let err = match judged {
+            Ok(result) => {
+                info!(score = result.score, "judged request.");
+                match publish(self.publisher.get().unwrap(), &properties, &result).await {
+                    Ok(()) => {
+                        log_unsettled(
+                            channel
+                                .basic_ack(delivery_tag, BasicAckOptions::default())
+                                .await,
+                        );
+                        return;
+                    }
+                    Err(err) => {
+                        error!("failed to publish result: {}", err);
+                        err
                     }
                 }
+            }
+            Err(err) => {
+                error!("an error occurred while judging: {}", err);
+                err
+            }
+        };
+        span.record("error", err.as_str());
+        span.record("otel.status_code", "ERROR");
+        self.retry(&channel, delivery_tag, &properties, &payload, &err)
+            .await;
+    }
 
-                error!("worker {}: malformed judge request.", self.id);
-                return Box::pin(async move {
-                    channel
-                        .basic_reject(delivery.delivery_tag, BasicRejectOptions::default())
-                        .await
-                        .unwrap();
-                    ()
-                });
+    /// Settles a request that failed to be judged by publishing it to the
+    /// retry queue or dead-lettering it once the broker confirms either, and
+    /// requeues it if neither is possible.
+    async fn retry(
+        &self,
+        channel: &Channel,
+        delivery_tag: LongLongUInt,
+        properties: &BasicProperties,
+        payload: &[u8],
+        err: &str,
+    ) {
+        if !channel.status().connected() {
+            warn!("lost the channel of judge request, the broker redelivers it.");
+            return;
+        }
+        match self
+            .publisher
+            .get()
+            .unwrap()
+            .retry(payload, properties, err)
+            .await
+        {
+            Ok(true) => error!("dead-lettered judge request after its last attempt."),
+            Ok(false) => info!("retrying judge request."),
+            Err(err) => {
+                error!("failed to retry judge request: {}", err);
+                log_unsettled(channel.basic_nack(delivery_tag, REQUEUE).await);
+                return;
             }
         }
+        log_unsettled(
+            channel
+                .basic_ack(delivery_tag, BasicAckOptions::default())
+                .await,
+        );
+    }
+}
+
+async fn publish(
+    publisher: &Queue,
+    properties: &BasicProperties,
+    result: &JudgeResult,
+) -> Result<(), String> {
+    let json = serde_json::to_string(result).map_err(|err| err.to_string())?;
+    publisher
+        .publish_result(
+            json.as_str(),
+            properties.reply_to().as_ref().map(|s| s.as_str()),
+            properties.correlation_id().as_ref().map(|s| s.as_str()),
+        )
+        .await
+        .map_err(|err| err.to_string())
+}
 
-        error!("worker {}: failed to consume message.", self.id);
-        Box::pin(async {})
+/// Logs a delivery that could not be acked, nacked or rejected, which happens
+/// once its channel was lost and the broker redelivers it anyway.
+fn log_unsettled(result: lapin::Result<()>) {
+    if let Err(err) = result {
+        warn!(
+            "failed to settle delivery, the broker redelivers it: {}.",
+            err
+        );
     }
 }
 
@@ -0,0 +1,254 @@
+use std::{
+    ffi::CString,
+    fs::{File, OpenOptions},
+    io::{self, Read},
+    mem,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, MetadataExt, OpenOptionsExt},
+        io::{AsRawFd, FromRawFd},
+    },
+    path::{Component, Path},
+};
+
+// Sandboxed programs may write to their workspace, so any entry below it,
+// including the directories leading to a file, may have been replaced with a
+// symlink to a host file by the time the judge opens it. Entries below a
+// workspace are therefore opened one component at a time relative to the
+// workspace, never following a symlink.
+
+/// Creates a file below `root` afresh for writing, along with the directories
+/// leading to it, which are owned like `root`. Whatever a program left at its
+/// path is removed rather than followed. Paths outside of `root` are trusted
+/// and created as they are.
+pub fn create(root: &Path, path: &Path) -> io::Result<File> {
+    let relative = match path.strip_prefix(root) {
+        Ok(relative) => relative,
+        Err(_) => return File::create(path),
+    };
+    let (dir, name) = parent(root, relative, true)?;
+    if unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::NotFound {
+            return Err(err);
+        }
+    }
+    // a program racing to put a symlink back only makes this fail
+    openat(
+        &dir,
+        &name,
+        libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL,
+        0o644,
+    )
+}
+
+/// Opens a regular file below `root` for reading. Paths outside of `root` are
+/// trusted and opened as they are.
+pub fn open(root: &Path, path: &Path) -> io::Result<File> {
+    let file = match path.strip_prefix(root) {
+        Ok(relative) => {
+            let (dir, name) = parent(root, relative, false)?;
+            // a named pipe left in place of the file must not block the judge
+            openat(&dir, &name, libc::O_RDONLY | libc::O_NONBLOCK, 0)?
+        }
+        Err(_) => File::open(path)?,
+    };
+    if !file.metadata()?.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a regular file", path.display()),
+        ));
+    }
+    Ok(file)
+}
+
+/// Reads a regular file below `root` as a string, like `open` does.
+pub fn read_to_string(root: &Path, path: &Path) -> io::Result<String> {
+    let mut content = String::new();
+    open(root, path)?.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Tells whether an entry exists below `root`, without following symlinks.
+pub fn exists(root: &Path, path: &Path) -> io::Result<bool> {
+    let relative = match path.strip_prefix(root) {
+        Ok(relative) => relative,
+        Err(_) => return Ok(path.exists()),
+    };
+    let (dir, name) = match parent(root, relative, false) {
+        Ok(parent) => parent,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    let mut stat = unsafe { mem::zeroed::<libc::stat>() };
+    if unsafe {
+        libc::fstatat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            &mut stat,
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    } == 0
+    {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.kind() == io::ErrorKind::NotFound {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+/// Opens a named pipe below `root`, for writing as well if asked to.
+pub fn open_pipe(root: &Path, path: &Path, write: bool) -> io::Result<File> {
+    let relative = path.strip_prefix(root).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is outside of the workspace", path.display()),
+        )
+    })?;
+    let (dir, name) = parent(root, relative, false)?;
+    let mode = if write { libc::O_RDWR } else { libc::O_RDONLY };
+    let file = openat(&dir, &name, mode, 0)?;
+    if !file.metadata()?.file_type().is_fifo() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a named pipe", path.display()),
+        ));
+    }
+    Ok(file)
+}
+
+/// Opens the directory holding `relative` below `root`, creating the missing
+/// ones if asked to, and returns it along with the name of the entry.
+fn parent(root: &Path, relative: &Path, create: bool) -> io::Result<(File, CString)> {
+    let mut names = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => names.push(CString::new(name.as_bytes())?),
+            Component::CurDir => (),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} leaves the workspace", relative.display()),
+                ))
+            }
+        }
+    }
+    let name = names.pop().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "the workspace is not a file")
+    })?;
+
+    let mut dir = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_CLOEXEC)
+        .open(root)?;
+    let owner = dir.metadata()?;
+    for component in names {
+        let created =
+            create && unsafe { libc::mkdirat(dir.as_raw_fd(), component.as_ptr(), 0o755) } == 0;
+        if create && !created {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::AlreadyExists {
+                return Err(err);
+            }
+        }
+        dir = openat(&dir, &component, libc::O_RDONLY | libc::O_DIRECTORY, 0)?;
+        if created && unsafe { libc::fchown(dir.as_raw_fd(), owner.uid(), owner.gid()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok((dir, name))
+}
+
+fn openat(dir: &File, name: &CString, flags: libc::c_int, mode: libc::c_uint) -> io::Result<File> {
+    let fd = unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            flags | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            mode,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, io::Write, os::unix::fs::symlink, path::PathBuf};
+
+    /// A workspace next to a host directory holding a secret file, with
+    /// symlinks to both planted in the workspace.
+    fn planted() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let (workspace, host) = (dir.path().join("workspace"), dir.path().join("host"));
+        fs::create_dir(&workspace).unwrap();
+        fs::create_dir(&host).unwrap();
+        fs::write(host.join("secret"), "secret").unwrap();
+        symlink(host.join("secret"), workspace.join("file")).unwrap();
+        symlink(&host, workspace.join("dir")).unwrap();
+        (dir, workspace, host)
+    }
+
+    #[test]
+    fn creates_over_symlinks_without_following_them() {
+        let (_dir, workspace, host) = planted();
+
+        create(&workspace, &workspace.join("file"))
+            .unwrap()
+            .write_all(b"output")
+            .unwrap();
+        assert_eq!(fs::read_to_string(host.join("secret")).unwrap(), "secret");
+        let metadata = fs::symlink_metadata(workspace.join("file")).unwrap();
+        assert!(metadata.file_type().is_file());
+        assert_eq!(
+            fs::read_to_string(workspace.join("file")).unwrap(),
+            "output"
+        );
+
+        assert!(create(&workspace, &workspace.join("dir/secret")).is_err());
+        assert!(create(&workspace, &workspace.join("dir/new")).is_err());
+        assert_eq!(fs::read_to_string(host.join("secret")).unwrap(), "secret");
+        assert!(!host.join("new").exists());
+    }
+
+    #[test]
+    fn refuses_to_open_symlinks() {
+        let (_dir, workspace, _host) = planted();
+
+        assert!(open(&workspace, &workspace.join("file")).is_err());
+        assert!(open(&workspace, &workspace.join("dir/secret")).is_err());
+        assert!(read_to_string(&workspace, &workspace.join("file")).is_err());
+        // the link itself is there, wherever it points to
+        assert!(exists(&workspace, &workspace.join("file")).unwrap());
+        assert!(exists(&workspace, &workspace.join("dir/secret")).is_err());
+    }
+
+    #[test]
+    fn stays_in_the_workspace() {
+        let (_dir, workspace, host) = planted();
+
+        let err = open(&workspace, &workspace.join("../host/secret")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = create(&workspace, &workspace.join("../host/secret")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(fs::read_to_string(host.join("secret")).unwrap(), "secret");
+    }
+
+    #[test]
+    fn opens_only_regular_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let pipe = dir.path().join("pipe");
+        nix::unistd::mkfifo(&pipe, nix::sys::stat::Mode::from_bits_truncate(0o600)).unwrap();
+
+        let err = open(dir.path(), &pipe).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(open_pipe(dir.path(), &pipe, true).is_ok());
+    }
+}
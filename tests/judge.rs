@@ -0,0 +1,77 @@
+//! Judges the programs in `fixtures/judge` with the `judge` subcommand,
+//! which sandboxes their runs and so needs Linux and root.
+#![cfg(target_os = "linux")]
+
+use serde_json::Value;
+use std::{fs, path::Path, process::Command};
+
+/// Judges a fixture program against the fixture config, or returns `None`
+/// when not running as root.
+fn judge(program: &str) -> Option<Value> {
+    if unsafe { libc::geteuid() } != 0 {
+        eprintln!("skipping, judging needs root.");
+        return None;
+    }
+
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/judge");
+    let mut config: Value =
+        serde_json::from_str(&fs::read_to_string(data.join("judge.json")).unwrap()).unwrap();
+    config["program"]["sources"][0]["path"] = Value::from(program);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("judge.json");
+    fs::write(&path, config.to_string()).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rayjudge"))
+        .arg("--data")
+        .arg(&data)
+        .arg("--workdir")
+        .arg(dir.path().join("work"))
+        .arg("judge")
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Some(serde_json::from_slice(&output.stdout).unwrap())
+}
+
+#[test]
+fn accepts_correct_output() {
+    let result = match judge("sum.c") {
+        Some(result) => result,
+        None => return,
+    };
+    assert_eq!(result["status"], "AC");
+    assert_eq!(result["score"], 10);
+    assert_eq!(result["stages"][0]["stdout"], "3\n");
+}
+
+#[test]
+fn rejects_wrong_output() {
+    let result = match judge("difference.c") {
+        Some(result) => result,
+        None => return,
+    };
+    assert_eq!(result["status"], "WA");
+    assert_eq!(result["score"], 0);
+    assert_eq!(result["stages"][0]["exit_code"], 0);
+}
+
+#[test]
+fn stops_endless_loops() {
+    let result = match judge("spin.c") {
+        Some(result) => result,
+        None => return,
+    };
+    assert_eq!(result["status"], "TLE");
+    assert_eq!(result["score"], 0);
+    // the time a killed run used is accounted as well
+    assert!(
+        result["stages"][0]["time"].as_u64().unwrap() >= 500,
+        "{}",
+        result
+    );
+}